@@ -0,0 +1,542 @@
+use crate::vm::{Coord, Delta, Vm};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// A Funge-98 fingerprint, loaded with `(` it binds its instructions to some of
+/// the letters `A` to `Z` for the IP executing it
+pub trait Fingerprint {
+    /// the name of the fingerprint, like `ROMA`
+    fn name(&self) -> &'static str;
+    /// the letters of its instructions
+    fn letters(&self) -> &'static str;
+    /// execute the instruction bound to `letter`
+    fn execute(&self, letter: char, vm: &mut Vm);
+}
+
+/// The id of a fingerprint is its name in base 256
+pub fn id(name: &str) -> i32 {
+    name.bytes().fold(0i32, |id, byte| {
+        id.wrapping_mul(256).wrapping_add(byte as i32)
+    })
+}
+
+/// The fingerprints every `Vm` knows
+pub fn builtins() -> Vec<Rc<dyn Fingerprint>> {
+    vec![
+        Rc::new(Roma),
+        Rc::new(Null),
+        Rc::new(Modu),
+        Rc::new(Bool),
+        Rc::new(Hrti::default()),
+        Rc::new(Fixp),
+        Rc::new(Strn),
+        Rc::new(Orth),
+    ]
+}
+
+/// Roman numerals
+struct Roma;
+
+impl Fingerprint for Roma {
+    fn name(&self) -> &'static str {
+        "ROMA"
+    }
+
+    fn letters(&self) -> &'static str {
+        "CDILMVX"
+    }
+
+    fn execute(&self, letter: char, vm: &mut Vm) {
+        vm.push(match letter {
+            'C' => 100,
+            'D' => 500,
+            'I' => 1,
+            'L' => 50,
+            'M' => 1000,
+            'V' => 5,
+            _ => 10,
+        });
+    }
+}
+
+/// Every letter reflects
+struct Null;
+
+impl Fingerprint for Null {
+    fn name(&self) -> &'static str {
+        "NULL"
+    }
+
+    fn letters(&self) -> &'static str {
+        "ABCDEFGHIJKLMNOPQRSTUVWXYZ"
+    }
+
+    fn execute(&self, _: char, vm: &mut Vm) {
+        vm.reflect();
+    }
+}
+
+/// Modulo arithmetic: pop a and b, then push the modulo of b by a.
+/// A modulo by zero pushes 0.
+struct Modu;
+
+impl Fingerprint for Modu {
+    fn name(&self) -> &'static str {
+        "MODU"
+    }
+
+    fn letters(&self) -> &'static str {
+        "MRU"
+    }
+
+    fn execute(&self, letter: char, vm: &mut Vm) {
+        let a = vm.pop();
+        let b = vm.pop();
+        if a == 0 {
+            return vm.push(0);
+        }
+        vm.push(match letter {
+            // the result has the sign of a
+            'M' => b.wrapping_sub(a.wrapping_mul(floor_div(b, a))),
+            // the result is never negative
+            'U' => b.wrapping_rem_euclid(a),
+            // the C remainder, the result has the sign of b
+            _ => b.wrapping_rem(a),
+        });
+    }
+}
+
+fn floor_div(a: i32, b: i32) -> i32 {
+    let q = a.wrapping_div(b);
+    if a.wrapping_rem(b) != 0 && (a < 0) != (b < 0) {
+        q - 1
+    } else {
+        q
+    }
+}
+
+/// Logic operations on the bits of the values
+struct Bool;
+
+impl Fingerprint for Bool {
+    fn name(&self) -> &'static str {
+        "BOOL"
+    }
+
+    fn letters(&self) -> &'static str {
+        "ANOX"
+    }
+
+    fn execute(&self, letter: char, vm: &mut Vm) {
+        if letter == 'N' {
+            let a = vm.pop();
+            return vm.push(!a);
+        }
+        let a = vm.pop();
+        let b = vm.pop();
+        vm.push(match letter {
+            'A' => b & a,
+            'O' => b | a,
+            _ => b ^ a,
+        });
+    }
+}
+
+/// High resolution timer, each IP has its own mark
+#[derive(Default)]
+struct Hrti {
+    marks: RefCell<HashMap<i32, Instant>>,
+}
+
+impl Fingerprint for Hrti {
+    fn name(&self) -> &'static str {
+        "HRTI"
+    }
+
+    fn letters(&self) -> &'static str {
+        "EGMST"
+    }
+
+    fn execute(&self, letter: char, vm: &mut Vm) {
+        let mut marks = self.marks.borrow_mut();
+        match letter {
+            // the timer counts microseconds
+            'G' => vm.push(1),
+            'M' => {
+                marks.insert(vm.ip(), Instant::now());
+            }
+            'T' => match marks.get(&vm.ip()) {
+                Some(mark) => vm.push(mark.elapsed().as_micros().min(i32::MAX as u128) as i32),
+                None => vm.reflect(),
+            },
+            'E' => {
+                marks.remove(&vm.ip());
+            }
+            _ => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                vm.push(now.subsec_micros() as i32);
+            }
+        }
+    }
+}
+
+/// Fixed point maths, the angles are in degrees and the values of the
+/// trigonometric functions are multiplied by 10000
+struct Fixp;
+
+impl Fingerprint for Fixp {
+    fn name(&self) -> &'static str {
+        "FIXP"
+    }
+
+    fn letters(&self) -> &'static str {
+        "ABCDIJNOPQRSTUVX"
+    }
+
+    fn execute(&self, letter: char, vm: &mut Vm) {
+        let a = vm.pop();
+        let value = match letter {
+            'A' | 'O' | 'X' => {
+                let b = vm.pop();
+                return vm.push(match letter {
+                    'A' => b & a,
+                    'O' => b | a,
+                    _ => b ^ a,
+                });
+            }
+            'R' => {
+                let b = vm.pop();
+                (b as f64).powf(a as f64)
+            }
+            'D' => {
+                use rand::Rng;
+                let n = match a {
+                    0 => 0,
+                    _ => rand::thread_rng().gen_range(0, a.unsigned_abs()) as i32,
+                };
+                return vm.push(n * a.signum());
+            }
+            'N' => return vm.push(a.wrapping_neg()),
+            'S' => return vm.push(a.signum()),
+            'V' => return vm.push(a.wrapping_abs()),
+            'P' => a as f64 * std::f64::consts::PI,
+            'Q' => (a as f64).sqrt(),
+            'C' => (a as f64 / 10000.0).to_radians().cos() * 10000.0,
+            'I' => (a as f64 / 10000.0).to_radians().sin() * 10000.0,
+            'T' => (a as f64 / 10000.0).to_radians().tan() * 10000.0,
+            'B' => (a as f64 / 10000.0).acos().to_degrees() * 10000.0,
+            'J' => (a as f64 / 10000.0).asin().to_degrees() * 10000.0,
+            _ => (a as f64 / 10000.0).atan().to_degrees() * 10000.0,
+        };
+        if value.is_finite() {
+            vm.push(value as i32);
+        } else {
+            vm.reflect();
+        }
+    }
+}
+
+/// Strings, they are on the stack like the `0gnirts` pushed by `"string"0`
+struct Strn;
+
+impl Strn {
+    fn pop_string(vm: &mut Vm) -> Vec<i32> {
+        let mut string = Vec::new();
+        loop {
+            match vm.pop() {
+                0 => return string,
+                c => string.push(c),
+            }
+        }
+    }
+
+    fn push_string(vm: &mut Vm, string: &[i32]) {
+        vm.push(0);
+        for &c in string.iter().rev() {
+            vm.push(c);
+        }
+    }
+
+    /// pop y then x, the coordinates are relative to the storage offset
+    fn pop_vector(vm: &mut Vm) -> Coord {
+        let y = vm.pop();
+        let x = vm.pop();
+        let offset = vm.offset();
+        Coord::from(x.wrapping_add(offset.x), y.wrapping_add(offset.y))
+    }
+}
+
+impl Fingerprint for Strn {
+    fn name(&self) -> &'static str {
+        "STRN"
+    }
+
+    fn letters(&self) -> &'static str {
+        "ACDFGILMNPRSV"
+    }
+
+    fn execute(&self, letter: char, vm: &mut Vm) {
+        match letter {
+            // append the second string to the top one
+            'A' => {
+                let mut top = Strn::pop_string(vm);
+                top.extend(Strn::pop_string(vm));
+                Strn::push_string(vm, &top);
+            }
+            'C' => {
+                let top = Strn::pop_string(vm);
+                let second = Strn::pop_string(vm);
+                vm.push(top.cmp(&second) as i32);
+            }
+            'D' => {
+                let string = Strn::pop_string(vm);
+                print!("{}", to_string(&string));
+            }
+            // the rest of the top string from the first occurrence of the
+            // second one
+            'F' => {
+                let top = Strn::pop_string(vm);
+                let second = Strn::pop_string(vm);
+                match (0..=top.len()).find(|&i| top[i..].starts_with(&second)) {
+                    Some(i) => Strn::push_string(vm, &top[i..]),
+                    None => vm.push(0),
+                }
+            }
+            // read a string going right up to a 0
+            'G' => {
+                let mut ptr = Strn::pop_vector(vm);
+                let (_, max) = vm.bounds();
+                let mut string = Vec::new();
+                while vm.cell(ptr) != 0 {
+                    if ptr.x >= max.x {
+                        return vm.reflect();
+                    }
+                    string.push(vm.cell(ptr));
+                    ptr.x += 1;
+                }
+                Strn::push_string(vm, &string);
+            }
+            // read a line of the input
+            'I' => {
+                let mut string = Vec::new();
+                while let Some(byte) = vm.read_byte() {
+                    if byte == b'\n' {
+                        break;
+                    }
+                    string.push(byte as i32);
+                }
+                Strn::push_string(vm, &string);
+            }
+            'L' => {
+                let n = vm.pop().max(0) as usize;
+                let string = Strn::pop_string(vm);
+                Strn::push_string(vm, &string[..n.min(string.len())]);
+            }
+            'M' => {
+                let n = vm.pop().max(0) as usize;
+                let start = vm.pop().max(0) as usize;
+                let string = Strn::pop_string(vm);
+                let start = start.min(string.len());
+                Strn::push_string(vm, &string[start..(start + n).min(string.len())]);
+            }
+            'N' => {
+                let string = Strn::pop_string(vm);
+                Strn::push_string(vm, &string);
+                vm.push(string.len() as i32);
+            }
+            // write the string and its 0 going right
+            'P' => {
+                let mut ptr = Strn::pop_vector(vm);
+                let string = Strn::pop_string(vm);
+                for c in string.into_iter().chain(Some(0)) {
+                    vm.set_cell(ptr, c);
+                    ptr.x += 1;
+                }
+            }
+            'R' => {
+                let n = vm.pop().max(0) as usize;
+                let string = Strn::pop_string(vm);
+                Strn::push_string(vm, &string[string.len().saturating_sub(n)..]);
+            }
+            'S' => {
+                let n = vm.pop();
+                let string: Vec<i32> = n.to_string().bytes().map(|b| b as i32).collect();
+                Strn::push_string(vm, &string);
+            }
+            // the number at the start of the string, like `atoi`
+            _ => {
+                let string = to_string(&Strn::pop_string(vm));
+                let string = string.trim_start();
+                let end = string
+                    .char_indices()
+                    .find(|&(i, c)| !(c.is_ascii_digit() || (i == 0 && (c == '-' || c == '+'))))
+                    .map_or(string.len(), |(i, _)| i);
+                vm.push(string[..end].parse().unwrap_or(0));
+            }
+        }
+    }
+}
+
+fn to_string(string: &[i32]) -> String {
+    string.iter().map(|&c| c as u8 as char).collect()
+}
+
+/// Orthogonal easement library, the instructions of Orthogonal
+struct Orth;
+
+impl Fingerprint for Orth {
+    fn name(&self) -> &'static str {
+        "ORTH"
+    }
+
+    fn letters(&self) -> &'static str {
+        "AEGOPSVWXYZ"
+    }
+
+    fn execute(&self, letter: char, vm: &mut Vm) {
+        match letter {
+            'A' => {
+                let a = vm.pop();
+                let b = vm.pop();
+                vm.push(b & a);
+            }
+            'O' => {
+                let a = vm.pop();
+                let b = vm.pop();
+                vm.push(b | a);
+            }
+            'E' => {
+                let a = vm.pop();
+                let b = vm.pop();
+                vm.push(b ^ a);
+            }
+            // like `g` and `p` with x and y swapped
+            'G' => {
+                let x = vm.pop();
+                let y = vm.pop();
+                let offset = vm.offset();
+                vm.push(vm.cell(Coord::from(
+                    x.wrapping_add(offset.x),
+                    y.wrapping_add(offset.y),
+                )));
+            }
+            'P' => {
+                let x = vm.pop();
+                let y = vm.pop();
+                let v = vm.pop();
+                let offset = vm.offset();
+                vm.set_cell(
+                    Coord::from(x.wrapping_add(offset.x), y.wrapping_add(offset.y)),
+                    v,
+                );
+            }
+            'X' => {
+                let x = vm.pop();
+                let ptr = vm.position();
+                vm.set_position(Coord::from(x, ptr.y));
+            }
+            'Y' => {
+                let y = vm.pop();
+                let ptr = vm.position();
+                vm.set_position(Coord::from(ptr.x, y));
+            }
+            'V' => {
+                let x = vm.pop();
+                let delta = vm.delta();
+                vm.set_delta(Delta { x, y: delta.y });
+            }
+            'W' => {
+                let y = vm.pop();
+                let delta = vm.delta();
+                vm.set_delta(Delta { x: delta.x, y });
+            }
+            // output a string
+            'S' => {
+                let string = Strn::pop_string(vm);
+                print!("{}", to_string(&string));
+            }
+            // skip the next cell if the value is 0, like `#`
+            _ => {
+                if vm.pop() == 0 {
+                    vm.skip();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::tests::stack;
+
+    /// The stack at the end of the code run once the fingerprint is loaded
+    fn loaded(name: &str, code: &str) -> Vec<i32> {
+        let reversed: String = name.chars().rev().collect();
+        stack(&format!("\"{}\"4($${}", reversed, code))
+    }
+
+    #[test]
+    fn ids() {
+        assert_eq!(id("ROMA"), 0x524f4d41);
+        assert_eq!(id("NULL"), 0x4e554c4c);
+    }
+
+    #[test]
+    fn roma() {
+        assert_eq!(loaded("ROMA", "MDCLXVI@"), [1000, 500, 100, 50, 10, 5, 1]);
+    }
+
+    #[test]
+    fn null() {
+        // `A` reflects to the `@` behind it
+        assert_eq!(loaded("NULL", "1#@A"), [1]);
+    }
+
+    #[test]
+    fn modu() {
+        assert_eq!(loaded("MODU", "07-3M07-3U07-3R@"), [2, 2, -1]);
+        assert_eq!(loaded("MODU", "50M50U50R@"), [0, 0, 0]);
+    }
+
+    #[test]
+    fn bool() {
+        assert_eq!(loaded("BOOL", "65A65O65X6N@"), [4, 7, 3, !6]);
+    }
+
+    #[test]
+    fn hrti() {
+        let values = loaded("HRTI", "GMT@");
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[0], 1);
+        assert!(values[1] >= 0);
+        // there is no mark once it’s erased
+        assert_eq!(loaded("HRTI", "ME1#@T"), [1]);
+    }
+
+    #[test]
+    fn fixp() {
+        assert_eq!(loaded("FIXP", "9Q5N23R5NV@"), [3, -5, 8, 5]);
+        // the square root of a negative value reflects
+        assert_eq!(loaded("FIXP", "1N#@Q"), []);
+    }
+
+    #[test]
+    fn strn() {
+        // "abcd" and its length
+        let mut expected = vec![0];
+        expected.extend("dcba".bytes().map(i32::from));
+        expected.push(4);
+        assert_eq!(loaded("STRN", "0\"dc\"0\"ba\"AN@"), expected);
+        assert_eq!(loaded("STRN", "0\"24-\"V@"), [-42]);
+    }
+
+    #[test]
+    fn orth() {
+        assert_eq!(loaded("ORTH", "65A65O65E00G@"), [4, 7, 3, '"' as i32]);
+    }
+}
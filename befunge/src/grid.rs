@@ -1,53 +1,191 @@
+use crate::vm::{Coord, Delta};
+use std::collections::HashMap;
+use std::convert::TryInto;
 use std::io::BufRead;
 
+const SPACE: i32 = ' ' as i32;
+
+/// The Funge-Space, unbounded in every direction. Only the cells which are
+/// not spaces are stored.
 #[derive(Debug)]
 pub struct Grid {
-    vec: Vec<Vec<i32>>,
+    cells: HashMap<Coord, i32>,
+    /// the corners of the smallest rectangle containing every cell we ever
+    /// stored, it never shrinks
+    min: Coord,
+    max: Coord,
 }
 
 impl Grid {
     pub fn from<R: BufRead>(reader: R) -> Self {
-        let mut grid: Vec<Vec<i32>> = Vec::with_capacity(40);
-        for line in reader.lines() {
+        let mut grid = Grid {
+            cells: HashMap::new(),
+            min: Coord::new(),
+            max: Coord::new(),
+        };
+        for (y, line) in reader.lines().enumerate() {
             let line = line.unwrap();
-            grid.push(
-                line.trim_end()
-                    .chars()
-                    .chain(std::iter::repeat(' '))
-                    .take(80)
-                    .map(|c| c as i32)
-                    .collect(),
-            );
+            for (x, c) in line.trim_end().chars().enumerate() {
+                grid.set(Coord::from(x as i32, y as i32), c as i32);
+            }
+        }
+        grid
+    }
+
+    /// The corners of the bounding box
+    pub fn bounds(&self) -> (Coord, Coord) {
+        (self.min, self.max)
+    }
+
+    /// Write a value in a cell, the bounding box grows to contain it
+    pub fn set(&mut self, i: Coord, value: i32) {
+        if value == SPACE {
+            self.cells.remove(&i);
+            return;
+        }
+        self.cells.insert(i, value);
+        self.min = Coord::from(self.min.x.min(i.x), self.min.y.min(i.y));
+        self.max = Coord::from(self.max.x.max(i.x), self.max.y.max(i.y));
+    }
+
+    /// Is the cell in the bounding box
+    pub fn contains(&self, i: Coord) -> bool {
+        (self.min.x..=self.max.x).contains(&i.x) && (self.min.y..=self.max.y).contains(&i.y)
+    }
+
+    /// Return the cell after `ptr` with Lahey-space wrapping: when we would
+    /// leave the bounding box we go back along the delta to the other side of
+    /// the box instead
+    pub fn wrap(&self, ptr: Coord, delta: Delta) -> Coord {
+        let next = (ptr.x as i64 + delta.x as i64, ptr.y as i64 + delta.y as i64);
+        if let (Ok(x), Ok(y)) = (next.0.try_into(), next.1.try_into()) {
+            if self.contains(Coord::from(x, y)) {
+                return Coord::from(x, y);
+            }
+        }
+
+        // the range of `k` for which `ptr - k * delta` is in the box
+        let mut steps = (i64::MIN, i64::MAX);
+        let axes = [
+            (ptr.x, delta.x, self.min.x, self.max.x),
+            (ptr.y, delta.y, self.min.y, self.max.y),
+        ];
+        for &(p, d, min, max) in &axes {
+            let (p, d, min, max) = (p as i64, d as i64, min as i64, max as i64);
+            if d == 0 {
+                if p < min || p > max {
+                    // we never cross the box, there is nothing else to execute
+                    return ptr;
+                }
+                continue;
+            }
+            let (low, high) = if d > 0 {
+                (ceil_div(p - max, d), floor_div(p - min, d))
+            } else {
+                (ceil_div(p - min, d), floor_div(p - max, d))
+            };
+            steps = (steps.0.max(low), steps.1.min(high));
+        }
+        if steps.0 > steps.1 {
+            return ptr;
         }
-        Grid { vec: grid }
+        // the farthest cell of the box behind us, or the closest one in
+        // front of us if we are outside of the box
+        Coord::from(
+            (ptr.x as i64 - steps.1 * delta.x as i64) as i32,
+            (ptr.y as i64 - steps.1 * delta.y as i64) as i32,
+        )
     }
+}
 
-    pub fn len(&self) -> usize {
-        self.vec.len()
+fn floor_div(a: i64, b: i64) -> i64 {
+    let q = a / b;
+    if a % b != 0 && (a < 0) != (b < 0) {
+        q - 1
+    } else {
+        q
     }
 }
 
-impl std::ops::Index<crate::vm::Coord> for Grid {
+fn ceil_div(a: i64, b: i64) -> i64 {
+    -floor_div(-a, b)
+}
+
+impl std::ops::Index<Coord> for Grid {
     type Output = i32;
-    fn index(&self, i: crate::vm::Coord) -> &Self::Output {
-        /*
-        if i.y >= self.vec.len() || i.x >= self.vec[i.y].len() {
-            println!("Argh!");
-            std::process::exit(-1);
-        }
-        */
-        &self.vec[i.y][i.x]
+    fn index(&self, i: Coord) -> &Self::Output {
+        self.cells.get(&i).unwrap_or(&SPACE)
     }
 }
 
-impl std::ops::IndexMut<crate::vm::Coord> for Grid {
-    fn index_mut(&mut self, i: crate::vm::Coord) -> &mut Self::Output {
-        /*
-        if i.y >= self.vec.len() || i.x >= self.vec[i.y].len() {
-            println!("Argh!");
-            std::process::exit(-1);
-        }
-        */
-        &mut self.vec[i.y][i.x]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RIGHT: Delta = Delta { x: 1, y: 0 };
+    const LEFT: Delta = Delta { x: -1, y: 0 };
+    const DOWN: Delta = Delta { x: 0, y: 1 };
+    const UP: Delta = Delta { x: 0, y: -1 };
+
+    fn grid(source: &str) -> Grid {
+        Grid::from(source.as_bytes())
+    }
+
+    #[test]
+    fn negative_coordinates() {
+        let mut grid = grid("ab");
+        grid.set(Coord::from(-3, -2), 'x' as i32);
+        assert_eq!(grid[Coord::from(-3, -2)], 'x' as i32);
+        assert_eq!(grid[Coord::from(-1, -1)], SPACE);
+        assert_eq!(grid.bounds(), (Coord::from(-3, -2), Coord::from(1, 0)));
+        // the bounding box never shrinks
+        grid.set(Coord::from(-3, -2), SPACE);
+        assert_eq!(grid[Coord::from(-3, -2)], SPACE);
+        assert_eq!(grid.bounds(), (Coord::from(-3, -2), Coord::from(1, 0)));
+    }
+
+    #[test]
+    fn wrap_inside_the_box() {
+        let grid = grid("abc\ndef\nghi");
+        assert_eq!(grid.wrap(Coord::from(1, 1), RIGHT), Coord::from(2, 1));
+        assert_eq!(grid.wrap(Coord::from(1, 1), UP), Coord::from(1, 0));
+    }
+
+    #[test]
+    fn wrap_to_the_other_side() {
+        let grid = grid("abc\ndef\nghi");
+        assert_eq!(grid.wrap(Coord::from(2, 1), RIGHT), Coord::from(0, 1));
+        assert_eq!(grid.wrap(Coord::from(0, 1), LEFT), Coord::from(2, 1));
+        assert_eq!(grid.wrap(Coord::from(1, 2), DOWN), Coord::from(1, 0));
+        assert_eq!(grid.wrap(Coord::from(1, 0), UP), Coord::from(1, 2));
+        // we go back along the delta, not to the opposite corner
+        let diagonal = Delta { x: 1, y: 1 };
+        assert_eq!(grid.wrap(Coord::from(2, 1), diagonal), Coord::from(1, 0));
+        let line = Grid::from("abcde".as_bytes());
+        let jump = Delta { x: 2, y: 0 };
+        assert_eq!(line.wrap(Coord::from(4, 0), jump), Coord::from(0, 0));
+        assert_eq!(line.wrap(Coord::from(3, 0), jump), Coord::from(1, 0));
+    }
+
+    #[test]
+    fn wrap_from_outside_the_box() {
+        let grid = grid("abc");
+        // the farthest cell behind us
+        assert_eq!(grid.wrap(Coord::from(5, 0), RIGHT), Coord::from(0, 0));
+        // the closest cell in front of us
+        assert_eq!(grid.wrap(Coord::from(5, 0), LEFT), Coord::from(2, 0));
+        // we never cross the box
+        assert_eq!(grid.wrap(Coord::from(0, 5), RIGHT), Coord::from(0, 5));
+    }
+
+    #[test]
+    fn wrap_with_huge_coordinates() {
+        let mut grid = grid("a");
+        grid.set(Coord::from(i32::MAX, 0), 'b' as i32);
+        assert_eq!(
+            grid.wrap(Coord::from(i32::MAX, 0), RIGHT),
+            Coord::from(0, 0)
+        );
+        assert_eq!(grid.wrap(Coord::from(0, 0), LEFT), Coord::from(i32::MAX, 0));
     }
 }
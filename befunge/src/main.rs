@@ -1,13 +1,41 @@
+mod fingerprint;
 mod grid;
 mod vm;
 
 use std::fs::File;
-use std::io::{stdin, BufReader};
+use std::io::{stdin, BufReader, Write};
+
+const USAGE: &str = "\
+usage:
+\t{exe} [options] [filename]
+
+The program is read on stdin without a filename.
+
+options:
+\t--98       execute the program as Befunge-98 instead of Befunge-93
+\t--trace    print on stderr each instruction executed
+";
+
+fn usage(exe: &str, error: String) {
+    println!("{}\n{}", error, USAGE.replace("{exe}", exe));
+}
 
 fn main() {
     let grid;
+    let mut mode = vm::Mode::Befunge93;
+    let mut trace = false;
+    let mut arg = None;
     let mut args = std::env::args();
-    let arg = args.nth(1);
+    let exe = args.next().unwrap();
+    for a in args {
+        match a.as_str() {
+            "--98" => mode = vm::Mode::Befunge98,
+            "--trace" => trace = true,
+            _ if a.starts_with('-') => return usage(&exe, format!("unknown option `{}`", a)),
+            _ if arg.is_some() => return usage(&exe, format!("unexpected argument `{}`", a)),
+            _ => arg = Some(a),
+        }
+    }
     if let Some(filename) = arg {
         let file = File::open(filename);
         if let Err(e) = file {
@@ -23,9 +51,31 @@ fn main() {
         grid = grid::Grid::from(reader);
     }
 
-    let mut vm = vm::Vm::new(grid);
+    let (_, max) = grid.bounds();
+    if mode == vm::Mode::Befunge93 && (max.x >= vm::WIDTH || max.y >= vm::HEIGHT) {
+        eprintln!(
+            "warning: the program is larger than {}x{}, the rest is ignored in Befunge-93",
+            vm::WIDTH,
+            vm::HEIGHT
+        );
+    }
+
+    let mut vm = vm::Vm::new(grid, mode);
 
-    loop {
-        vm.cycle();
+    while let Some(step) = vm.cycle() {
+        if trace {
+            eprintln!(
+                "ip {} at {},{}: {}",
+                step.ip,
+                step.ptr.x,
+                step.ptr.y,
+                std::char::from_u32(step.op as u32).unwrap_or(' ')
+            );
+        }
+    }
+    if let Some(code) = vm.exit_code() {
+        // `exit` doesn’t flush the output
+        std::io::stdout().flush().unwrap();
+        std::process::exit(code);
     }
 }
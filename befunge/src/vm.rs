@@ -1,30 +1,57 @@
-use std::io::Read;
+use crate::fingerprint::{self, Fingerprint};
+use std::collections::HashSet;
+use std::io::{BufReader, Bytes, Read, Stdin};
+use std::iter::Peekable;
+use std::rc::Rc;
 
+/// The version of Befunge executed by the `Vm`
 #[derive(Copy, Clone, PartialEq)]
-enum Direction {
-    Left,
-    Down,
-    Up,
-    Right,
+pub enum Mode {
+    /// Befunge-93 on its 80x25 playfield, the cells outside of it can’t be used
+    Befunge93,
+    /// Funge-98 with two dimensions, the unknown instructions reflect the pointer
+    Befunge98,
 }
 
-use Direction::*;
+/// The move of the pointer on each step
+#[derive(Copy, Clone, PartialEq)]
+pub struct Delta {
+    pub x: i32,
+    pub y: i32,
+}
+
+const LEFT: Delta = Delta { x: -1, y: 0 };
+const DOWN: Delta = Delta { x: 0, y: 1 };
+const UP: Delta = Delta { x: 0, y: -1 };
+const RIGHT: Delta = Delta { x: 1, y: 0 };
+
+/// The size of the Befunge-93 playfield
+pub const WIDTH: i32 = 80;
+pub const HEIGHT: i32 = 25;
 
-impl rand::distributions::Distribution<Direction> for rand::distributions::Standard {
-    fn sample<R: rand::Rng + ?Sized>(&self, rng: &mut R) -> Direction {
+/// The largest count of `k`, `{`, `}` and `u`, a larger one reflects the IP
+/// like an instruction we can’t execute instead of running out of memory
+const MAX_COUNT: u32 = 1 << 20;
+
+/// The instructions which only exist in Funge-98
+const FUNGE_98: &str = "abcdefhijklmnoqrstuwxyz'[]{};=()";
+
+impl rand::distributions::Distribution<Delta> for rand::distributions::Standard {
+    fn sample<R: rand::Rng + ?Sized>(&self, rng: &mut R) -> Delta {
         match rng.gen_range(0, 4) {
-            0 => Left,
-            1 => Down,
-            2 => Right,
-            _ => Up,
+            0 => LEFT,
+            1 => DOWN,
+            2 => RIGHT,
+            _ => UP,
         }
     }
 }
 
-#[derive(Copy, Clone)]
+/// A position in the Funge-Space, which extends in every direction
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub struct Coord {
-    pub x: usize,
-    pub y: usize,
+    pub x: i32,
+    pub y: i32,
 }
 
 impl Coord {
@@ -32,75 +59,177 @@ impl Coord {
         Coord { x: 0, y: 0 }
     }
 
-    pub fn from(x: usize, y: usize) -> Self {
+    pub fn from(x: i32, y: i32) -> Self {
         Coord { x, y }
     }
 }
 
-/// we are defining addition between a coordinate and a direction
-impl std::ops::Add<Direction> for Coord {
-    type Output = Self;
-
-    fn add(self, dir: Direction) -> Self {
-        match dir {
-            Left => Coord {
-                x: self.x - 1,
-                y: self.y,
-            },
-            Down => Coord {
-                x: self.x,
-                y: self.y + 1,
-            },
-            Up => Coord {
-                x: self.x,
-                y: self.y - 1,
-            },
-            Right => Coord {
-                x: self.x + 1,
-                y: self.y,
-            },
-        }
-    }
+/// An instruction pointer, in Funge-98 many of them run concurrently on the
+/// same Funge-Space
+#[derive(Clone)]
+struct Ip {
+    /// a unique number, the first IP is 0
+    id: i32,
+    ptr: Coord,
+    delta: Delta,
+    /// the top of the stack stack, the only stack in Befunge-93
+    stack: Vec<i32>,
+    /// the stacks under `stack`, the last one is the second on the stack stack
+    stacks: Vec<Vec<i32>>,
+    /// added to the coordinates of `g` and `p`
+    offset: Delta,
+    /// every cell is pushed on the stack until the next `"`
+    string: bool,
+    /// the fingerprints loaded for each letter from `A` to `Z`, the last one
+    /// gives its meaning to the letter
+    semantics: Vec<Vec<Rc<dyn Fingerprint>>>,
 }
 
-impl std::ops::AddAssign<Direction> for Coord {
-    fn add_assign(&mut self, dir: Direction) {
-        *self = *self + dir;
-    }
+/// What an IP did during a cycle
+pub struct Step {
+    /// the id of the IP
+    pub ip: i32,
+    /// where the IP was
+    pub ptr: Coord,
+    /// the value of the cell it executed, or pushed in string mode
+    pub op: i32,
 }
 
 pub struct Vm {
     grid: crate::grid::Grid,
-    ptr: Coord,
-    dir: Direction,
-    stack: Vec<i32>,
+    mode: Mode,
+    /// the IP executing its instruction
+    ip: Ip,
+    /// the other IPs in the order they run, `ip` is between the first
+    /// `current` ones and the rest of them
+    ips: Vec<Ip>,
+    current: usize,
+    /// the id of the next IP created with `t`
+    next_id: i32,
+    /// the current IP executed `@`
+    killed: bool,
+    /// every IP is dead
+    finished: bool,
+    /// the exit code given to `q`, which stops every IP
+    exit_code: Option<i32>,
+    /// the input of `&` and `~`, stdin is only locked while we read it
+    input: Peekable<Bytes<BufReader<Stdin>>>,
+    /// the cells on which we already warned about a Funge-98 instruction
+    warned: HashSet<Coord>,
+    /// the fingerprints which can be loaded with `(`
+    fingerprints: Vec<Rc<dyn Fingerprint>>,
 }
 
 impl Vm {
-    pub fn new(grid: crate::grid::Grid) -> Self {
+    pub fn new(grid: crate::grid::Grid, mode: Mode) -> Self {
         Vm {
             grid,
-            ptr: Coord::new(),
-            dir: Direction::Right,
-            stack: Vec::new(),
+            mode,
+            ip: Ip {
+                id: 0,
+                ptr: Coord::new(),
+                delta: RIGHT,
+                stack: Vec::new(),
+                stacks: Vec::new(),
+                offset: Delta { x: 0, y: 0 },
+                string: false,
+                semantics: vec![Vec::new(); 26],
+            },
+            ips: Vec::new(),
+            current: 0,
+            next_id: 1,
+            killed: false,
+            finished: false,
+            exit_code: None,
+            input: BufReader::new(std::io::stdin()).bytes().peekable(),
+            warned: HashSet::new(),
+            fingerprints: fingerprint::builtins(),
         }
     }
 
-    pub fn cycle(&mut self) {
-        let op = self.grid[self.ptr];
-        // println!("stack: {:?}", self.stack);
+    /// Execute the instruction of the current IP then let the next IP run,
+    /// return what was executed or `None` once every IP is dead
+    pub fn cycle(&mut self) -> Option<Step> {
+        if self.finished {
+            return None;
+        }
+        // the spaces and the code between `;` are skipped in no time
+        if self.mode == Mode::Befunge98 && !self.ip.string {
+            self.ip.ptr = self.instruction_from(self.ip.ptr);
+        }
+        let op = self.grid[self.ip.ptr];
+        let step = Step {
+            ip: self.ip.id,
+            ptr: self.ip.ptr,
+            op,
+        };
+        // println!("stack: {:?}", self.ip.stack);
         // println!("op: {}", op as u8 as char);
-        // println!("coord: {}, {}\n", self.ptr.x, self.ptr.y);
-        match op as u8 as char {
+        // println!("coord: {}, {}\n", self.ip.ptr.x, self.ip.ptr.y);
+        if self.ip.string {
+            self.string(op);
+        } else {
+            self.execute(op);
+        }
+        if self.killed {
+            self.kill();
+        } else if !self.finished {
+            self.step();
+            self.switch();
+        }
+        Some(step)
+    }
+
+    /// The exit code of the program if it was ended by `q`
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    /// Let the next IP in the list run, after the last one comes the first one
+    fn switch(&mut self) {
+        if self.ips.is_empty() {
+            return;
+        }
+        if self.current < self.ips.len() {
+            std::mem::swap(&mut self.ip, &mut self.ips[self.current]);
+            self.current += 1;
+        } else {
+            std::mem::swap(&mut self.ip, &mut self.ips[0]);
+            self.ips.rotate_left(1);
+            self.current = 0;
+        }
+    }
+
+    /// Remove the current IP, the next one in the list runs
+    fn kill(&mut self) {
+        self.killed = false;
+        if self.ips.is_empty() {
+            self.finished = true;
+        } else if self.current < self.ips.len() {
+            self.ip = self.ips.remove(self.current);
+        } else {
+            self.ip = self.ips.remove(0);
+            self.current = 0;
+        }
+    }
+
+    /// execute an instruction without moving to the next one
+    fn execute(&mut self, op: i32) {
+        let instruction = match self.mode {
+            Mode::Befunge93 => op as u8 as char,
+            // the 98 instructions can’t be confused with a truncated value
+            Mode::Befunge98 => std::char::from_u32(op as u32).unwrap_or('\0'),
+        };
+        match instruction {
             // flow control
-            '>' => self.dir = Right,
-            '<' => self.dir = Left,
-            '^' => self.dir = Up,
-            'v' => self.dir = Down,
-            '?' => self.dir = rand::random(),
+            '>' => self.ip.delta = RIGHT,
+            '<' => self.ip.delta = LEFT,
+            '^' => self.ip.delta = UP,
+            'v' => self.ip.delta = DOWN,
+            '?' => self.ip.delta = rand::random(),
             '_' => self.right_if(),
             '|' => self.down_if(),
-            '"' => self.string(),
+            '"' => self.ip.string = true,
             '#' => self.bridge(),
             '@' => self.quit(),
 
@@ -118,7 +247,7 @@ impl Vm {
             'p' => self.put(),
             'g' => self.get(),
             '0' | '1' | '2' | '3' | '4' | '5' | '6' | '7' | '8' | '9' => {
-                self.stack.push(op - '0' as i32);
+                self.ip.stack.push(op - '0' as i32);
             }
 
             // I/O
@@ -127,61 +256,107 @@ impl Vm {
             '&' => self.ask_num(),
             '~' => self.ask_char(),
 
-            _ => (),
+            ' ' => (),
+            _ if self.mode == Mode::Befunge93 => self.warn(instruction),
+            _ => self.execute_98(instruction),
         }
-        self.step();
     }
 
-    /// move to the current direction
-    /// when you are going out of the grid yuow rap to the other side of the grid
-    fn step(&mut self) {
-        self.ptr = match (self.ptr, self.dir) {
-            (Coord { x: 0, y }, Left) => Coord { x: 79, y },
-            (Coord { x: 79, y }, Right) => Coord { x: 0, y },
-            (Coord { x, y: 0 }, Up) => Coord {
-                x,
-                y: self.grid.len() - 1,
-            },
-            (ptr, dir) => {
-                // we can’t do this at compile time in the match
-                if dir == Down && ptr.y == self.grid.len() - 1 {
-                    Coord { x: ptr.x, y: 0 }
-                } else {
-                    ptr + dir
-                }
+    /// execute an instruction added by Funge-98
+    fn execute_98(&mut self, instruction: char) {
+        match instruction {
+            // flow control
+            '[' => self.turn_left(),
+            ']' => self.turn_right(),
+            'r' => self.reflect(),
+            'x' => self.absolute_delta(),
+            'w' => self.compare(),
+            'j' => self.jump_forward(),
+            'k' => self.iterate(),
+            ';' => self.jump_over(),
+            'z' => (),
+            'q' => self.quit_with_code(),
+            't' => self.split(),
+            '(' => self.load(),
+            ')' => self.unload(),
+            'A'..='Z' => self.fingerprint(instruction),
+
+            // stack
+            'a' | 'b' | 'c' | 'd' | 'e' | 'f' => {
+                self.ip.stack.push(instruction as i32 - 'a' as i32 + 10);
             }
+            '\'' => self.fetch(),
+            's' => self.store(),
+            'n' => self.ip.stack.clear(),
+            '{' => self.begin_block(),
+            '}' => self.end_block(),
+            'u' => self.stack_under_stack(),
+
+            // every unknown instruction acts like `r`
+            _ => self.reflect(),
         }
     }
 
-    /// end program execution
+    /// the next cell in the current direction
+    /// when you are going out of the grid you wrap to the other side of the grid,
+    /// which is a torus of 80 columns in Befunge-93 and the bounding box of
+    /// the Funge-Space in Befunge-98
+    fn next(&self, ptr: Coord) -> Coord {
+        self.next_by(ptr, self.ip.delta)
+    }
+
+    /// the next cell with another delta
+    fn next_by(&self, ptr: Coord, delta: Delta) -> Coord {
+        match self.mode {
+            Mode::Befunge93 => Coord {
+                x: (ptr.x + delta.x).rem_euclid(WIDTH),
+                y: (ptr.y + delta.y).rem_euclid(HEIGHT),
+            },
+            Mode::Befunge98 => self.grid.wrap(ptr, delta),
+        }
+    }
+
+    /// move to the current direction
+    fn step(&mut self) {
+        self.ip.ptr = self.next(self.ip.ptr);
+    }
+
+    /// end the execution of the current IP, the program ends with the last IP
     fn quit(&mut self) {
-        std::process::exit(0);
+        self.killed = true;
     }
 
     /// Pop a value; move right if value=0, left otherwise
     fn right_if(&mut self) {
-        let val = self.stack.pop().unwrap_or(0);
+        let val = self.ip.stack.pop().unwrap_or(0);
         match val {
-            0 => self.dir = Right,
-            _ => self.dir = Left,
+            0 => self.ip.delta = RIGHT,
+            _ => self.ip.delta = LEFT,
         }
     }
 
     /// Pop a value; move down if value=0, up otherwise
     fn down_if(&mut self) {
-        let val = self.stack.pop().unwrap_or(0);
+        let val = self.ip.stack.pop().unwrap_or(0);
         match val {
-            0 => self.dir = Down,
-            _ => self.dir = Up,
+            0 => self.ip.delta = DOWN,
+            _ => self.ip.delta = UP,
         }
     }
 
-    /// Start string mode: push each character's ASCII value all the way up to the next "
-    fn string(&mut self) {
-        self.ptr += self.dir;
-        while self.grid[self.ptr] != '"' as i32 {
-            self.stack.push(self.grid[self.ptr]);
-            self.step();
+    /// String mode: push each character's ASCII value all the way up to the next "
+    /// In Befunge-98 many spaces in a row are pushed as a single space
+    fn string(&mut self, val: i32) {
+        if val == '"' as i32 {
+            self.ip.string = false;
+            return;
+        }
+        self.ip.stack.push(val);
+        if self.mode == Mode::Befunge98 && val == ' ' as i32 {
+            let start = self.ip.ptr;
+            while self.grid[self.next(self.ip.ptr)] == val && self.next(self.ip.ptr) != start {
+                self.step();
+            }
         }
     }
 
@@ -192,133 +367,800 @@ impl Vm {
 
     /// duplicate top value on stack
     fn duplicate(&mut self) {
-        let val = self.stack.pop().unwrap_or(0);
-        self.stack.push(val);
-        self.stack.push(val);
+        let val = self.ip.stack.pop().unwrap_or(0);
+        self.ip.stack.push(val);
+        self.ip.stack.push(val);
     }
 
     /// Swap two values on top of the stack
     fn swap(&mut self) {
-        let v1 = self.stack.pop().unwrap_or(0);
-        let v2 = self.stack.pop().unwrap_or(0);
-        self.stack.push(v2);
-        self.stack.push(v1);
+        let v1 = self.ip.stack.pop().unwrap_or(0);
+        let v2 = self.ip.stack.pop().unwrap_or(0);
+        self.ip.stack.push(v2);
+        self.ip.stack.push(v1);
     }
 
     /// delete top value off stack
     fn delete(&mut self) {
-        self.stack.pop();
+        self.ip.stack.pop();
     }
 
-    /// Addition: Pop a and b, then push a + b
+    /// Addition: Pop a and b, then push a + b, wrapping around on overflow
     fn add(&mut self) {
-        let a = self.stack.pop().unwrap_or(0);
-        let b = self.stack.pop().unwrap_or(0);
-        self.stack.push(a + b);
+        let a = self.ip.stack.pop().unwrap_or(0);
+        let b = self.ip.stack.pop().unwrap_or(0);
+        self.ip.stack.push(a.wrapping_add(b));
     }
 
-    /// Subtraction: Pop a and b, then push b - a
+    /// Subtraction: Pop a and b, then push b - a, wrapping around on overflow
     fn sub(&mut self) {
-        let a = self.stack.pop().unwrap_or(0);
-        let b = self.stack.pop().unwrap_or(0);
-        self.stack.push(b - a);
+        let a = self.ip.stack.pop().unwrap_or(0);
+        let b = self.ip.stack.pop().unwrap_or(0);
+        self.ip.stack.push(b.wrapping_sub(a));
     }
 
-    /// Multiplication: Pop a and b, then push a * b
+    /// Multiplication: Pop a and b, then push a * b, wrapping around on overflow
     fn mul(&mut self) {
-        let a = self.stack.pop().unwrap_or(0);
-        let b = self.stack.pop().unwrap_or(0);
-        self.stack.push(b * a);
+        let a = self.ip.stack.pop().unwrap_or(0);
+        let b = self.ip.stack.pop().unwrap_or(0);
+        self.ip.stack.push(b.wrapping_mul(a));
     }
 
     /// Integer division: Pop a and b, then push b / a, rounded towards 0.
+    /// A division by zero pushes 0, `i32::MIN / -1` wraps around to `i32::MIN`.
     fn div(&mut self) {
-        let a = self.stack.pop().unwrap_or(0);
-        let b = self.stack.pop().unwrap_or(0);
-        self.stack.push(b / a);
+        let a = self.ip.stack.pop().unwrap_or(0);
+        let b = self.ip.stack.pop().unwrap_or(0);
+        match a {
+            0 => self.ip.stack.push(0),
+            _ => self.ip.stack.push(b.wrapping_div(a)),
+        }
     }
 
     /// Modulo: Pop a and b, then push the remainder of the integer division of b / a.
+    /// A modulo by zero pushes 0, like `i32::MIN % -1`.
     fn modulo(&mut self) {
-        let a = self.stack.pop().unwrap_or(0);
-        let b = self.stack.pop().unwrap_or(0);
-        self.stack.push(b % a);
+        let a = self.ip.stack.pop().unwrap_or(0);
+        let b = self.ip.stack.pop().unwrap_or(0);
+        match a {
+            0 => self.ip.stack.push(0),
+            _ => self.ip.stack.push(b.wrapping_rem(a)),
+        }
     }
 
     /// Logical NOT: Pop a value. If the value is zero, push 1; otherwise, push zero.
     fn not(&mut self) {
-        let val = self.stack.pop().unwrap_or(0);
+        let val = self.ip.stack.pop().unwrap_or(0);
         match val {
-            0 => self.stack.push(1),
-            _ => self.stack.push(0),
+            0 => self.ip.stack.push(1),
+            _ => self.ip.stack.push(0),
         }
     }
 
     /// Greater than: Pop a and b, then push 1 if b > a, otherwise zero.
     fn greater_than(&mut self) {
-        let a = self.stack.pop().unwrap_or(0);
-        let b = self.stack.pop().unwrap_or(0);
+        let a = self.ip.stack.pop().unwrap_or(0);
+        let b = self.ip.stack.pop().unwrap_or(0);
 
         if b > a {
-            self.stack.push(1);
+            self.ip.stack.push(1);
         } else {
-            self.stack.push(0);
+            self.ip.stack.push(0);
         }
     }
 
     /// A "put" call (a way to store a value for later use). Pop y, x, and v,
     /// then change the character at (x,y) in the program to the character with
     /// ASCII value v
+    /// In Befunge-93 nothing happens outside of the playfield
     fn put(&mut self) {
-        if self.stack.len() < 3 {
-            println!("Not enough element in the stack");
-            std::process::exit(0);
-        }
-        let y = self.stack.pop().unwrap();
-        let x = self.stack.pop().unwrap();
-        let v = self.stack.pop().unwrap();
+        let y = self.ip.stack.pop().unwrap_or(0) + self.ip.offset.y;
+        let x = self.ip.stack.pop().unwrap_or(0) + self.ip.offset.x;
+        let v = self.ip.stack.pop().unwrap_or(0);
 
-        self.grid[Coord::from(x as usize, y as usize)] = v;
+        if self.mode == Mode::Befunge98 || in_playfield(x, y) {
+            self.grid.set(Coord::from(x, y), v);
+        }
     }
 
     /// A "get" call (a way to retrieve data in storage). Pop y and x, then
     /// push ASCII value of the character at that position in the program
+    /// In Befunge-93 we push 0 outside of the playfield
     fn get(&mut self) {
-        let y = self.stack.pop().unwrap_or(0);
-        let x = self.stack.pop().unwrap_or(0);
+        let y = self.ip.stack.pop().unwrap_or(0) + self.ip.offset.y;
+        let x = self.ip.stack.pop().unwrap_or(0) + self.ip.offset.x;
 
-        self.stack
-            .push(self.grid[Coord::from(x as usize, y as usize)]);
+        if self.mode == Mode::Befunge98 || in_playfield(x, y) {
+            self.ip.stack.push(self.grid[Coord::from(x, y)]);
+        } else {
+            self.ip.stack.push(0);
+        }
     }
 
     /// Pop value and output as an integer followed by a space
     fn print_int(&mut self) {
-        if self.stack.len() < 1 {
-            println!("Not enough element in the stack");
-            std::process::exit(0);
-        }
-        print!("{} ", self.stack.pop().unwrap());
+        print!("{} ", self.ip.stack.pop().unwrap_or(0));
     }
 
     /// Pop value and output as ASCII character
     fn print(&mut self) {
-        if self.stack.len() < 1 {
-            println!("Not enough element in the stack");
-            std::process::exit(0);
-        }
-        print!("{}", self.stack.pop().unwrap() as u8 as char);
+        print!("{}", self.ip.stack.pop().unwrap_or(0) as u8 as char);
     }
 
     /// Ask user for a number and push it
+    /// The digits are read up to the first non digit, if there is no number
+    /// to read we push -1 in Befunge-93 and reflect in Befunge-98
     fn ask_num(&mut self) {
-        println!("Input a number: ");
-        let val = std::io::stdin().bytes().next().unwrap_or(Ok(0)).unwrap() as i32;
-        self.stack.push(val);
+        // everything before the number is ignored
+        while let Some(Ok(byte)) = self.input.peek() {
+            if byte.is_ascii_digit() {
+                break;
+            }
+            self.input.next();
+        }
+        let mut val: Option<i32> = None;
+        while let Some(Ok(byte)) = self.input.peek() {
+            if !byte.is_ascii_digit() {
+                break;
+            }
+            let digit = (byte - b'0') as i32;
+            val = Some(val.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+            self.input.next();
+        }
+        match val {
+            Some(val) => self.ip.stack.push(val),
+            None if self.mode == Mode::Befunge98 => self.reflect(),
+            None => self.ip.stack.push(-1),
+        }
     }
 
-    /// Ask user for a number and push it
+    /// Ask user for a character and push it
+    /// At the end of the input we push -1 in Befunge-93 and reflect in Befunge-98
     fn ask_char(&mut self) {
-        let val = std::io::stdin().bytes().next().unwrap_or(Ok(0)).unwrap() as i32;
-        self.stack.push(val);
+        match self.input.next() {
+            Some(val) => self.ip.stack.push(val.unwrap() as i32),
+            None if self.mode == Mode::Befunge98 => self.reflect(),
+            None => self.ip.stack.push(-1),
+        }
+    }
+
+    /// Warn once per cell when a Funge-98 instruction is executed in
+    /// Befunge-93, where it does nothing
+    fn warn(&mut self, instruction: char) {
+        if FUNGE_98.contains(instruction) && self.warned.insert(self.ip.ptr) {
+            eprintln!(
+                "warning: `{}` at {},{} is a Befunge-98 instruction, it does nothing in Befunge-93",
+                instruction, self.ip.ptr.x, self.ip.ptr.y
+            );
+        }
+    }
+
+    /// Turn left: rotate the delta by 90 degrees counterclockwise
+    fn turn_left(&mut self) {
+        self.ip.delta = Delta {
+            x: self.ip.delta.y,
+            y: -self.ip.delta.x,
+        };
+    }
+
+    /// Turn right: rotate the delta by 90 degrees clockwise
+    fn turn_right(&mut self) {
+        self.ip.delta = Delta {
+            x: -self.ip.delta.y,
+            y: self.ip.delta.x,
+        };
+    }
+
+    /// Reverse: go back in the opposite direction
+    pub fn reflect(&mut self) {
+        self.ip.delta = Delta {
+            x: -self.ip.delta.x,
+            y: -self.ip.delta.y,
+        };
+    }
+
+    /// Absolute delta: pop dy and dx, then use them as the new delta
+    fn absolute_delta(&mut self) {
+        let y = self.ip.stack.pop().unwrap_or(0);
+        let x = self.ip.stack.pop().unwrap_or(0);
+        self.ip.delta = Delta { x, y };
+    }
+
+    /// Compare: pop b and a, then turn left if a < b, right if a > b
+    fn compare(&mut self) {
+        let b = self.ip.stack.pop().unwrap_or(0);
+        let a = self.ip.stack.pop().unwrap_or(0);
+        match a.cmp(&b) {
+            std::cmp::Ordering::Less => self.turn_left(),
+            std::cmp::Ordering::Greater => self.turn_right(),
+            std::cmp::Ordering::Equal => (),
+        }
+    }
+
+    /// Jump forward: pop n, then skip n cells, backward if n is negative
+    fn jump_forward(&mut self) {
+        let n = self.ip.stack.pop().unwrap_or(0);
+        if n < 0 {
+            self.reflect();
+        }
+        for _ in 0..n.unsigned_abs() {
+            self.step();
+        }
+        if n < 0 {
+            self.reflect();
+        }
+    }
+
+    /// Iterate: pop n, then execute the next instruction n times without
+    /// moving, or skip it if n is 0. We reflect if n is negative or too large.
+    fn iterate(&mut self) {
+        let n = self.ip.stack.pop().unwrap_or(0);
+        let target = self.instruction_from(self.next(self.ip.ptr));
+        if n < 0 || n.unsigned_abs() > MAX_COUNT {
+            return self.reflect();
+        }
+        let (ptr, delta) = (self.ip.ptr, self.ip.delta);
+        let op = self.grid[target];
+        for _ in 0..n {
+            self.execute(op);
+        }
+        // we go past the instruction unless it moved us somewhere else
+        if self.ip.ptr == ptr && self.ip.delta == delta {
+            self.ip.ptr = target;
+        }
+    }
+
+    /// the position of the first instruction from `start`, the spaces and the
+    /// code between `;` are not instructions
+    fn instruction_from(&self, start: Coord) -> Coord {
+        // we can’t go through more cells than this without coming back to a
+        // cell we already saw
+        let (min, max) = self.grid.bounds();
+        let limit = (max.x as i64 - min.x as i64) + (max.y as i64 - min.y as i64) + 3;
+
+        let mut ptr = start;
+        let mut jumping = false;
+        for _ in 0..limit {
+            match self.grid[ptr] {
+                c if c == ';' as i32 => jumping = !jumping,
+                c if c != ' ' as i32 && !jumping => return ptr,
+                _ => (),
+            }
+            ptr = self.next(ptr);
+        }
+        // there is nothing to execute on our way
+        start
+    }
+
+    /// Jump over: skip everything up to the next `;`
+    fn jump_over(&mut self) {
+        self.step();
+        while self.grid[self.ip.ptr] != ';' as i32 {
+            self.step();
+        }
+    }
+
+    /// Split: create a new IP going in the opposite direction with a copy of
+    /// the stack stack, it runs before the current IP from the next cycle
+    fn split(&mut self) {
+        let mut child = self.ip.clone();
+        child.id = self.next_id;
+        self.next_id += 1;
+        child.delta = Delta {
+            x: -self.ip.delta.x,
+            y: -self.ip.delta.y,
+        };
+        child.ptr = self.next_by(child.ptr, child.delta);
+        self.ips.insert(self.current, child);
+        self.current += 1;
+    }
+
+    /// Load a fingerprint: pop a count n and the n values of its id, then
+    /// bind its instructions to their letters, push its id and 1.
+    /// We reflect if we don’t know the fingerprint.
+    fn load(&mut self) {
+        let fingerprint = match self.pop_fingerprint() {
+            Some(fingerprint) => fingerprint,
+            None => return self.reflect(),
+        };
+        for letter in fingerprint.letters().bytes() {
+            self.ip.semantics[(letter - b'A') as usize].push(fingerprint.clone());
+        }
+        self.ip.stack.push(fingerprint::id(fingerprint.name()));
+        self.ip.stack.push(1);
+    }
+
+    /// Unload a fingerprint: pop a count n and the n values of its id, then
+    /// unbind the last instructions bound to each of its letters.
+    /// We reflect if we don’t know the fingerprint.
+    fn unload(&mut self) {
+        let fingerprint = match self.pop_fingerprint() {
+            Some(fingerprint) => fingerprint,
+            None => return self.reflect(),
+        };
+        for letter in fingerprint.letters().bytes() {
+            self.ip.semantics[(letter - b'A') as usize].pop();
+        }
+    }
+
+    /// Pop the id of a fingerprint, the first value popped is the first letter
+    /// of its name
+    fn pop_fingerprint(&mut self) -> Option<Rc<dyn Fingerprint>> {
+        let n = self.ip.stack.pop().unwrap_or(0);
+        let mut id = 0i32;
+        for _ in 0..n.max(0) {
+            let val = self.ip.stack.pop().unwrap_or(0);
+            id = id.wrapping_mul(256).wrapping_add(val);
+        }
+        self.fingerprints
+            .iter()
+            .find(|f| fingerprint::id(f.name()) == id)
+            .cloned()
+    }
+
+    /// Execute the instruction bound to a letter by the last fingerprint
+    /// loaded, or reflect if there is none
+    fn fingerprint(&mut self, letter: char) {
+        let index = letter as usize - 'A' as usize;
+        match self.ip.semantics[index].last().cloned() {
+            Some(fingerprint) => fingerprint.execute(letter, self),
+            None => self.reflect(),
+        }
+    }
+
+    /// Quit: pop a value and end program execution with it as exit code,
+    /// every IP stops
+    fn quit_with_code(&mut self) {
+        self.exit_code = Some(self.ip.stack.pop().unwrap_or(0));
+        self.finished = true;
+    }
+
+    /// Fetch character: push the value of the next cell and skip it
+    fn fetch(&mut self) {
+        self.step();
+        self.ip.stack.push(self.grid[self.ip.ptr]);
+    }
+
+    /// Store character: pop a value, write it in the next cell and skip it
+    fn store(&mut self) {
+        self.step();
+        let val = self.ip.stack.pop().unwrap_or(0);
+        self.grid.set(self.ip.ptr, val);
+    }
+
+    /// Begin block: pop n, then push a new stack on the stack stack with the
+    /// n values on top of the old stack. The storage offset is saved on the
+    /// old stack and the next cell becomes the origin of `g` and `p`.
+    /// We reflect if n is too large.
+    fn begin_block(&mut self) {
+        let n = self.ip.stack.pop().unwrap_or(0);
+        if n.unsigned_abs() > MAX_COUNT {
+            return self.reflect();
+        }
+        let toss = match n {
+            n if n >= 0 => take(&mut self.ip.stack, n as usize),
+            n => {
+                self.ip.stack.extend((0..n.unsigned_abs()).map(|_| 0));
+                Vec::new()
+            }
+        };
+        self.ip.stack.push(self.ip.offset.x);
+        self.ip.stack.push(self.ip.offset.y);
+        self.ip
+            .stacks
+            .push(std::mem::replace(&mut self.ip.stack, toss));
+        let next = self.next(self.ip.ptr);
+        self.ip.offset = Delta {
+            x: next.x,
+            y: next.y,
+        };
+    }
+
+    /// End block: pop n, then remove the stack on top of the stack stack and
+    /// move its n values on top of the stack under it. The storage offset
+    /// saved by `{` is restored. We reflect if n is too large.
+    fn end_block(&mut self) {
+        if self.ip.stacks.is_empty() {
+            return self.reflect();
+        }
+        let n = self.ip.stack.pop().unwrap_or(0);
+        if n.unsigned_abs() > MAX_COUNT {
+            return self.reflect();
+        }
+        let mut soss = self.ip.stacks.pop().unwrap();
+        let y = soss.pop().unwrap_or(0);
+        let x = soss.pop().unwrap_or(0);
+        self.ip.offset = Delta { x, y };
+        match n {
+            n if n >= 0 => soss.extend(take(&mut self.ip.stack, n as usize)),
+            n => soss.truncate(soss.len().saturating_sub(n.unsigned_abs() as usize)),
+        }
+        self.ip.stack = soss;
+    }
+
+    /// Stack under stack: pop n, then move n values from the stack under the
+    /// top of the stack stack to its top one by one, or the other way if n is
+    /// negative. We reflect if n is too large.
+    fn stack_under_stack(&mut self) {
+        if self.ip.stacks.is_empty() {
+            return self.reflect();
+        }
+        let n = self.ip.stack.pop().unwrap_or(0);
+        if n.unsigned_abs() > MAX_COUNT {
+            return self.reflect();
+        }
+        let soss = self.ip.stacks.last_mut().unwrap();
+        for _ in 0..n.unsigned_abs() {
+            if n > 0 {
+                self.ip.stack.push(soss.pop().unwrap_or(0));
+            } else {
+                soss.push(self.ip.stack.pop().unwrap_or(0));
+            }
+        }
+    }
+}
+
+/// What the fingerprints can do with the `Vm`
+impl Vm {
+    pub fn pop(&mut self) -> i32 {
+        self.ip.stack.pop().unwrap_or(0)
+    }
+
+    pub fn push(&mut self, val: i32) {
+        self.ip.stack.push(val);
+    }
+
+    /// the id of the current IP
+    pub fn ip(&self) -> i32 {
+        self.ip.id
+    }
+
+    pub fn position(&self) -> Coord {
+        self.ip.ptr
+    }
+
+    pub fn set_position(&mut self, ptr: Coord) {
+        self.ip.ptr = ptr;
+    }
+
+    pub fn delta(&self) -> Delta {
+        self.ip.delta
+    }
+
+    pub fn set_delta(&mut self, delta: Delta) {
+        self.ip.delta = delta;
+    }
+
+    /// the storage offset of the current IP
+    pub fn offset(&self) -> Delta {
+        self.ip.offset
+    }
+
+    pub fn cell(&self, i: Coord) -> i32 {
+        self.grid[i]
+    }
+
+    pub fn set_cell(&mut self, i: Coord, val: i32) {
+        self.grid.set(i, val);
+    }
+
+    /// the corners of the bounding box of the Funge-Space
+    pub fn bounds(&self) -> (Coord, Coord) {
+        self.grid.bounds()
+    }
+
+    /// skip the next cell like `#`
+    pub fn skip(&mut self) {
+        self.step();
+    }
+
+    /// read a byte of the input
+    pub fn read_byte(&mut self) -> Option<u8> {
+        self.input.next().and_then(Result::ok)
+    }
+}
+
+/// Is the cell in the Befunge-93 playfield
+fn in_playfield(x: i32, y: i32) -> bool {
+    (0..WIDTH).contains(&x) && (0..HEIGHT).contains(&y)
+}
+
+/// Remove the n values on top of the stack and return them in the same order,
+/// the missing values are zeros
+fn take(stack: &mut Vec<i32>, n: usize) -> Vec<i32> {
+    let mut values = vec![0; n.saturating_sub(stack.len())];
+    values.extend(stack.drain(stack.len().saturating_sub(n)..));
+    values
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::grid::Grid;
+
+    /// Run a program until every IP is dead
+    pub(crate) fn run(source: &str, mode: Mode) -> Vm {
+        let mut vm = Vm::new(Grid::from(source.as_bytes()), mode);
+        for _ in 0..10_000 {
+            if vm.cycle().is_none() {
+                return vm;
+            }
+        }
+        panic!("`{}` never ends", source);
+    }
+
+    /// The stack of the last IP at the end of a Funge-98 program
+    pub(crate) fn stack(source: &str) -> Vec<i32> {
+        run(source, Mode::Befunge98).ip.stack
+    }
+
+    /// Run the first cycles of a Funge-98 program
+    fn start(source: &str, cycles: usize) -> Vm {
+        let mut vm = Vm::new(Grid::from(source.as_bytes()), Mode::Befunge98);
+        for _ in 0..cycles {
+            vm.cycle().unwrap();
+        }
+        vm
+    }
+
+    #[test]
+    fn hex_digits() {
+        assert_eq!(stack("abcdef@"), [10, 11, 12, 13, 14, 15]);
+    }
+
+    #[test]
+    fn fetch_and_store() {
+        // the fetched cell is skipped, it’s not executed
+        assert_eq!(stack("'A'@@"), ['A' as i32, '@' as i32]);
+        let vm = run("'ss @", Mode::Befunge98);
+        assert_eq!(vm.grid[Coord::from(3, 0)], 's' as i32);
+        assert!(vm.ip.stack.is_empty());
+    }
+
+    #[test]
+    fn jump_over() {
+        assert_eq!(stack("1;2;3@"), [1, 3]);
+        // the jump takes no cycle
+        let vm = start("1; 2 ;3@", 2);
+        assert_eq!(vm.ip.stack, [1, 3]);
+    }
+
+    #[test]
+    fn iterate() {
+        assert_eq!(stack("14k:@"), [1, 1, 1, 1, 1]);
+        // the spaces before the instruction are skipped
+        assert_eq!(stack("13k :@"), [1, 1, 1, 1]);
+        // 0 skips the instruction
+        assert_eq!(stack("50k12@"), [5, 2]);
+    }
+
+    #[test]
+    fn iterate_reflects_on_a_bad_count() {
+        let vm = start("01-k@", 4);
+        assert!(vm.ip.delta == LEFT);
+        let vm = start("ff*:*f*f*k@", 10);
+        assert!(vm.ip.delta == LEFT);
+        assert!(vm.ip.stack.is_empty());
+    }
+
+    #[test]
+    fn unknown_instructions_reflect() {
+        // back to `1` then to the other side of the line
+        assert_eq!(stack("1r2@"), [1, 1]);
+        assert_eq!(stack("1X2@"), [1, 1]);
+        assert_eq!(stack("1\u{e9}2@"), [1, 1]);
+    }
+
+    #[test]
+    fn blocks() {
+        let vm = run("1232{@", Mode::Befunge98);
+        assert_eq!(vm.ip.stack, [2, 3]);
+        assert_eq!(vm.ip.stacks, [[1, 0, 0]]);
+        assert_eq!((vm.ip.offset.x, vm.ip.offset.y), (5, 0));
+
+        let vm = run("1232{1}@", Mode::Befunge98);
+        assert_eq!(vm.ip.stack, [1, 3]);
+        assert!(vm.ip.stacks.is_empty());
+        assert_eq!((vm.ip.offset.x, vm.ip.offset.y), (0, 0));
+
+        // the cell after `{` is the origin of `g`
+        assert_eq!(stack("0{00g@"), ['0' as i32]);
+    }
+
+    #[test]
+    fn blocks_reflect_on_a_bad_count() {
+        // there is no stack under the stack
+        let vm = start("1}@", 2);
+        assert!(vm.ip.delta == LEFT);
+        let vm = start("ff*:*f*f*{@", 10);
+        assert!(vm.ip.delta == LEFT);
+        assert!(vm.ip.stacks.is_empty());
+    }
+
+    #[test]
+    fn put_and_get_anywhere() {
+        let vm = run("501-02-p01-02-g@", Mode::Befunge98);
+        assert_eq!(vm.ip.stack, [5]);
+        assert_eq!(vm.grid.bounds().0, Coord::from(-1, -2));
+    }
+
+    #[test]
+    fn the_box_grows_with_put() {
+        // without the `@` put on the left the IP would loop on the second line
+        let vm = run("'@02-1pv\n       <", Mode::Befunge98);
+        assert_eq!(vm.ip.ptr, Coord::from(-2, 1));
+    }
+
+    #[test]
+    fn get_outside_of_the_playfield() {
+        assert_eq!(run("99*1g@", Mode::Befunge93).ip.stack, [0]);
+        assert_eq!(run("99*1g@", Mode::Befunge98).ip.stack, [' ' as i32]);
+        // below the lines of the file but still in the playfield
+        assert_eq!(run("799+g@", Mode::Befunge93).ip.stack, [' ' as i32]);
+    }
+
+    #[test]
+    fn put_outside_of_the_playfield() {
+        let vm = run("799*1p99*1g@", Mode::Befunge93);
+        assert_eq!(vm.ip.stack, [0]);
+        assert_eq!(vm.grid[Coord::from(81, 1)], ' ' as i32);
+        assert_eq!(run("799*1p99*1g@", Mode::Befunge98).ip.stack, [7]);
+        assert_eq!(run("7999+p999+g@", Mode::Befunge93).ip.stack, [7]);
+    }
+
+    #[test]
+    fn playfield_is_a_torus_of_80_by_25() {
+        // the `@` put on the last row is only reached by going up
+        let vm = run("88*883*p^", Mode::Befunge93);
+        assert_eq!(vm.ip.ptr, Coord::from(8, 24));
+        // the `@` put on the last column is only reached by going right
+        let vm = run("88*99*2-1pv\n          >", Mode::Befunge93);
+        assert_eq!(vm.ip.ptr, Coord::from(79, 1));
+    }
+
+    #[test]
+    fn funge_98_instructions_are_ignored_in_befunge_93() {
+        let vm = run("1a2@", Mode::Befunge93);
+        assert_eq!(vm.ip.stack, [1, 2]);
+        assert!(vm.warned.contains(&Coord::from(1, 0)));
+    }
+
+    /// The IP and the instruction of each cycle of a Funge-98 program
+    fn trace(source: &str) -> Vec<(i32, char)> {
+        let mut vm = Vm::new(Grid::from(source.as_bytes()), Mode::Befunge98);
+        let mut steps = Vec::new();
+        while let Some(step) = vm.cycle() {
+            steps.push((step.ip, step.op as u8 as char));
+            assert!(steps.len() < 1000, "`{}` never ends", source);
+        }
+        steps
+    }
+
+    #[test]
+    fn split_runs_the_child_first() {
+        // the child starts on the cell before `t` and goes left
+        assert_eq!(
+            trace("1t2@"),
+            [(0, '1'), (0, 't'), (1, '1'), (0, '2'), (1, '@'), (0, '@')]
+        );
+        // in each tick the IPs run in the order of the list, where a new IP
+        // goes just before the IP which created it
+        assert_eq!(
+            trace("v\n>#1t#2t3@"),
+            [
+                (0, 'v'),
+                (0, '>'),
+                (0, '#'),
+                (0, 't'),
+                (1, '1'),
+                (0, '#'),
+                (1, '#'),
+                (0, 't'),
+                (1, '@'),
+                (2, '2'),
+                (0, '3'),
+                (2, '#'),
+                (0, '@'),
+                (2, '1'),
+                (2, '#'),
+                (2, '@')
+            ]
+        );
+    }
+
+    #[test]
+    fn end_kills_the_current_ip() {
+        assert_eq!(
+            trace("2t@"),
+            [(0, '2'), (0, 't'), (1, '2'), (0, '@'), (1, '@')]
+        );
+        // the child has a copy of the stack
+        assert_eq!(stack("2t@"), [2, 2]);
+    }
+
+    #[test]
+    fn quit_stops_every_ip() {
+        assert_eq!(trace("7tq"), [(0, '7'), (0, 't'), (1, '7'), (0, 'q')]);
+        assert_eq!(run("7tq", Mode::Befunge98).exit_code(), Some(7));
+    }
+
+    /// The code of `(` or `)` for a fingerprint
+    fn fingerprint_op(name: &str, op: char) -> String {
+        let reversed: String = name.chars().rev().collect();
+        format!("\"{}\"{}{}", reversed, name.len(), op)
+    }
+
+    #[test]
+    fn load_and_unload_every_fingerprint() {
+        for fingerprint in fingerprint::builtins() {
+            let name = fingerprint.name();
+            let vm = run(&format!("{}@", fingerprint_op(name, '(')), Mode::Befunge98);
+            assert_eq!(vm.ip.stack, [fingerprint::id(name), 1], "{}", name);
+            for (i, semantics) in vm.ip.semantics.iter().enumerate() {
+                let letter = (b'A' + i as u8) as char;
+                assert_eq!(
+                    semantics.len(),
+                    fingerprint.letters().contains(letter) as usize,
+                    "{} {}",
+                    name,
+                    letter
+                );
+            }
+
+            let source = format!(
+                "{}{}@",
+                fingerprint_op(name, '('),
+                fingerprint_op(name, ')')
+            );
+            let vm = run(&source, Mode::Befunge98);
+            assert_eq!(vm.ip.stack, [fingerprint::id(name), 1], "{}", name);
+            assert!(vm.ip.semantics.iter().all(Vec::is_empty), "{}", name);
+        }
+    }
+
+    #[test]
+    fn unknown_fingerprints_reflect() {
+        // `(` and `)` go back to the `@` behind them
+        assert_eq!(stack("\"OOF\"3#@("), []);
+        assert_eq!(stack("\"OOF\"3#@)"), []);
+    }
+
+    #[test]
+    fn unloaded_letters_reflect() {
+        assert_eq!(stack("\"AMOR\"4(\"AMOR\"4)$$#@I"), []);
+    }
+
+    #[test]
+    fn semantics_are_stacked() {
+        let roma = fingerprint::id("ROMA");
+        let modu = fingerprint::id("MODU");
+        let vm = run("\"AMOR\"4(\"UDOM\"4(@", Mode::Befunge98);
+        let names: Vec<_> = vm.ip.semantics[(b'M' - b'A') as usize]
+            .iter()
+            .map(|f| f.name())
+            .collect();
+        assert_eq!(names, ["ROMA", "MODU"]);
+        // once MODU is unloaded `M` is the one of ROMA again
+        assert_eq!(
+            stack("\"AMOR\"4(\"UDOM\"4(\"UDOM\"4)M@"),
+            [roma, 1, modu, 1, 1000]
+        );
+    }
+
+    #[test]
+    fn each_ip_has_its_semantics() {
+        // the child keeps ROMA after its parent unloaded it
+        let source = "\"AMOR\"4(v\n        >#vt\"AMOR\"4)@\n          >zzzzzzzzzzzzI@";
+        let vm = run(source, Mode::Befunge98);
+        assert_eq!(vm.ip.id, 1);
+        assert_eq!(vm.ip.stack, [fingerprint::id("ROMA"), 1, 1]);
+    }
+
+    #[test]
+    fn quit_with_code() {
+        let vm = run("7q@", Mode::Befunge98);
+        assert_eq!(vm.exit_code(), Some(7));
+        assert!(vm.ip.stack.is_empty());
+        assert_eq!(run("7@", Mode::Befunge98).exit_code(), None);
     }
 }
@@ -0,0 +1,135 @@
+use num_bigint::{BigInt, Sign};
+use std::fmt;
+use std::str::FromStr;
+
+/// What happens when a bounded cell goes past its minimum or maximum value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    /// Wrap around: `255 + 1 == 0` with 8-bit cells
+    Wrap,
+    /// Stay on the bound: `255 + 1 == 255` with 8-bit cells
+    Saturate,
+    /// Stop the program with a runtime error
+    Error,
+}
+
+/// The bitwise operations of Extended Brainfuck, the binary ones take the
+/// storage register as their second operand
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bitwise {
+    /// Shift the bits one position to the left, the highest bit is lost (`{`)
+    ShiftLeft,
+    /// Shift the bits one position to the right (`}`)
+    ShiftRight,
+    Not,
+    Xor,
+    And,
+    Or,
+}
+
+/// A value stored in the tape.
+/// Every operation returns `None` if the cell overflowed and `overflow` is `Overflow::Error`.
+pub trait Cell: Clone + Default + PartialEq + fmt::Debug + fmt::Display + FromStr {
+    /// `false` for the cells which can hold arbitrarily large values
+    const BOUNDED: bool;
+
+    /// Add `value` to the cell
+    fn add(&self, value: i32, overflow: Overflow) -> Option<Self>;
+    /// Add `other * factor` to the cell
+    fn add_mul(&self, other: &Self, factor: i32, overflow: Overflow) -> Option<Self>;
+
+    fn is_zero(&self) -> bool;
+    /// The value stored by an input of `byte`
+    fn from_byte(byte: u8) -> Self;
+    /// The byte sent by an output of the cell, this is its lowest 8 bits
+    fn to_byte(&self) -> u8;
+    /// Apply a bitwise operation to the cell and `other`, it never overflows
+    fn bitwise(&self, op: Bitwise, other: &Self) -> Self;
+}
+
+macro_rules! bounded_cell {
+    ($($t:ty),*) => {$(
+        impl Cell for $t {
+            const BOUNDED: bool = true;
+
+            fn add(&self, value: i32, overflow: Overflow) -> Option<Self> {
+                self.add_mul(&1, value, overflow)
+            }
+
+            fn add_mul(&self, other: &Self, factor: i32, overflow: Overflow) -> Option<Self> {
+                let value = *self as i128 + *other as i128 * factor as i128;
+                match overflow {
+                    _ if (0..=<$t>::MAX as i128).contains(&value) => Some(value as $t),
+                    Overflow::Wrap => Some(value as $t),
+                    Overflow::Saturate if value < 0 => Some(0),
+                    Overflow::Saturate => Some(<$t>::MAX),
+                    Overflow::Error => None,
+                }
+            }
+
+            fn is_zero(&self) -> bool {
+                *self == 0
+            }
+
+            fn from_byte(byte: u8) -> Self {
+                byte as $t
+            }
+
+            fn to_byte(&self) -> u8 {
+                *self as u8
+            }
+
+            fn bitwise(&self, op: Bitwise, other: &Self) -> Self {
+                match op {
+                    Bitwise::ShiftLeft => *self << 1,
+                    Bitwise::ShiftRight => *self >> 1,
+                    Bitwise::Not => !*self,
+                    Bitwise::Xor => *self ^ *other,
+                    Bitwise::And => *self & *other,
+                    Bitwise::Or => *self | *other,
+                }
+            }
+        }
+    )*};
+}
+
+bounded_cell!(u8, u16, u32, u64);
+
+/// A cell without any bound, it never overflow
+impl Cell for BigInt {
+    const BOUNDED: bool = false;
+
+    fn add(&self, value: i32, _overflow: Overflow) -> Option<Self> {
+        Some(self + value)
+    }
+
+    fn add_mul(&self, other: &Self, factor: i32, _overflow: Overflow) -> Option<Self> {
+        Some(self + other * factor)
+    }
+
+    fn is_zero(&self) -> bool {
+        self.sign() == Sign::NoSign
+    }
+
+    fn from_byte(byte: u8) -> Self {
+        BigInt::from(byte)
+    }
+
+    fn to_byte(&self) -> u8 {
+        // the bytes are in two’s complement so -1 gives 255 like the other cells
+        self.to_signed_bytes_le()[0]
+    }
+
+    /// The operations work on the infinite two’s complement representation,
+    /// `!0 == -1` and `-1 >> 1 == -1`
+    fn bitwise(&self, op: Bitwise, other: &Self) -> Self {
+        match op {
+            Bitwise::ShiftLeft => self << 1,
+            Bitwise::ShiftRight => self >> 1,
+            Bitwise::Not => !self,
+            Bitwise::Xor => self ^ other,
+            Bitwise::And => self & other,
+            Bitwise::Or => self | other,
+        }
+    }
+}
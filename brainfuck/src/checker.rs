@@ -0,0 +1,294 @@
+use crate::cell::Overflow;
+use crate::compiler;
+use crate::parser::Program;
+use crate::tape::Boundary;
+use crate::vm::Config;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops::Range;
+
+/// A problem found in the program without running it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Problem {
+    /// The cell is always 0 when we reach this loop
+    DeadLoop,
+    /// `[]` never ends if it’s entered
+    EmptyLoop,
+    /// Each iteration of the loop moves the memory pointer by this offset
+    UnbalancedLoop(i32),
+    /// The memory pointer always goes to this cell, outside of the tape
+    OutOfTape(i64),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::DeadLoop => write!(f, "this loop never runs, the cell is always 0 here"),
+            Problem::EmptyLoop => write!(f, "this empty loop never ends if it runs"),
+            Problem::UnbalancedLoop(offset) => write!(
+                f,
+                "each iteration of this loop moves the memory pointer by {}",
+                offset
+            ),
+            Problem::OutOfTape(cell) => write!(
+                f,
+                "the memory pointer goes to the cell {}, outside of the tape",
+                cell
+            ),
+        }
+    }
+}
+
+/// A problem and the index in `Program::code` of the instruction causing it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Finding {
+    pub instruction: usize,
+    pub problem: Problem,
+}
+
+/// What we know about the cells, the offsets are relative to the memory pointer
+#[derive(Debug, Clone)]
+enum Cells {
+    /// All the cells are 0 except maybe these ones
+    Zero(BTreeSet<i32>),
+    /// Only these cells are known to be 0
+    Unknown(BTreeSet<i32>),
+}
+
+impl Cells {
+    fn is_zero(&self, offset: i32) -> bool {
+        match self {
+            Cells::Zero(written) => !written.contains(&offset),
+            Cells::Unknown(zero) => zero.contains(&offset),
+        }
+    }
+
+    /// The cell at `offset` may not be 0 anymore
+    fn write(&mut self, offset: i32) {
+        match self {
+            Cells::Zero(written) => written.insert(offset),
+            Cells::Unknown(zero) => zero.remove(&offset),
+        };
+    }
+
+    /// The cell under the memory pointer is 0
+    fn clear(&mut self) {
+        match self {
+            Cells::Zero(written) => written.remove(&0),
+            Cells::Unknown(zero) => zero.insert(0),
+        };
+    }
+
+    /// The memory pointer moved by `offset`
+    fn shift(&mut self, offset: i32) {
+        let set = match self {
+            Cells::Zero(set) | Cells::Unknown(set) => set,
+        };
+        *set = set.iter().map(|o| o - offset).collect();
+    }
+}
+
+/// What we know when we reach an instruction
+#[derive(Debug, Clone)]
+struct State {
+    /// the memory pointer, if it’s always the same
+    pointer: Option<i64>,
+    cells: Cells,
+    /// the offsets of the cells which are never 0
+    nonzero: BTreeSet<i32>,
+}
+
+impl State {
+    fn unknown() -> Self {
+        State {
+            pointer: None,
+            cells: Cells::Unknown(BTreeSet::new()),
+            nonzero: BTreeSet::new(),
+        }
+    }
+
+    /// The cell at `offset` may be anything
+    fn write(&mut self, offset: i32) {
+        self.cells.write(offset);
+        self.nonzero.remove(&offset);
+    }
+
+    /// The memory pointer moved by `offset`
+    fn shift(&mut self, offset: i32) {
+        self.cells.shift(offset);
+        self.nonzero = self.nonzero.iter().map(|o| o - offset).collect();
+    }
+}
+
+/// Find the problems of the program by following the loops given by its
+/// brackets, like the `Vm` would do
+pub fn check(program: &Program, config: &Config) -> Vec<Finding> {
+    let mut checker = Checker {
+        program,
+        config,
+        findings: Vec::new(),
+    };
+    let mut state = State {
+        pointer: Some(0),
+        cells: Cells::Zero(BTreeSet::new()),
+        nonzero: BTreeSet::new(),
+    };
+    checker.block(0..program.code.len(), &mut state);
+    checker.findings.sort_by_key(|f| f.instruction);
+    checker.findings
+}
+
+struct Checker<'a> {
+    program: &'a Program,
+    config: &'a Config,
+    findings: Vec<Finding>,
+}
+
+impl<'a> Checker<'a> {
+    fn report(&mut self, instruction: usize, problem: Problem) {
+        self.findings.push(Finding {
+            instruction,
+            problem,
+        });
+    }
+
+    /// Follow the code in `range` from `state`, which is updated to what we
+    /// know at the end of the code
+    fn block(&mut self, range: Range<usize>, state: &mut State) {
+        let code = &self.program.code;
+        let mut i = range.start;
+        while i < range.end {
+            match code[i] {
+                '+' | '-' => {
+                    let (value, len) = compiler::fold(&code[i..range.end], '+', '-');
+                    if value != 0 {
+                        // even 8-bit cells can’t go back to 0, but a
+                        // saturating cell stays at 0 when it’s decremented
+                        let nonzero = state.cells.is_zero(0)
+                            && value.abs() < 256
+                            && (value > 0 || self.config.overflow != Overflow::Saturate);
+                        state.write(0);
+                        if nonzero {
+                            state.nonzero.insert(0);
+                        }
+                    }
+                    i += len;
+                    continue;
+                }
+                '>' | '<' => {
+                    // the `Vm` checks where each run of `>` or `<` ends
+                    let (offset, len) = compiler::run(&code[i..range.end], '>');
+                    state.shift(offset);
+                    state.pointer = state.pointer.map(|p| p + offset as i64);
+                    if let Some(pointer) = state.pointer {
+                        if self.config.boundary == Boundary::Classic
+                            && !(0..self.config.tape_size as i64).contains(&pointer)
+                        {
+                            self.report(i, Problem::OutOfTape(pointer));
+                            state.pointer = None;
+                        }
+                    }
+                    i += len;
+                    continue;
+                }
+                '[' => {
+                    let end = self.program.brackets[i].unwrap();
+                    self.loop_(i, end, state);
+                    i = end;
+                }
+                // the body of a procedure can run anywhere
+                '(' => {
+                    let end = self.program.brackets[i].unwrap();
+                    self.block(i + 1..end, &mut State::unknown());
+                    i = end;
+                }
+                // a procedure or another thread can do anything
+                ':' => *state = State::unknown(),
+                'Y' => {
+                    state.cells = Cells::Unknown(BTreeSet::new());
+                    state.nonzero.clear();
+                }
+                ',' | 'm' | '!' | '{' | '}' | '~' | '^' | '&' | '|' => state.write(0),
+                '0' => {
+                    state.cells.clear();
+                    state.nonzero.remove(&0);
+                }
+                // the rest of the block never runs
+                '@' => return,
+                _ => (),
+            }
+            i += 1;
+        }
+    }
+
+    /// Follow the loop between the brackets `start` and `end`
+    fn loop_(&mut self, start: usize, end: usize, state: &mut State) {
+        if start + 1 == end {
+            self.report(start, Problem::EmptyLoop);
+        }
+        if state.cells.is_zero(0) {
+            self.report(start, Problem::DeadLoop);
+            return;
+        }
+
+        // the body may never run, we only know where it goes outside of the
+        // tape if the loop is always entered
+        let mut body = state.clone();
+        if !state.nonzero.contains(&0) {
+            body.pointer = None;
+        }
+        match self.effect(start + 1..end) {
+            // each iteration starts on the same cell
+            Some((0, written)) => {
+                for offset in written {
+                    state.write(offset);
+                    body.write(offset);
+                }
+                self.block(start + 1..end, &mut body);
+            }
+            effect => {
+                if let Some((offset, _)) = effect {
+                    self.report(start, Problem::UnbalancedLoop(offset));
+                }
+                // only the first iteration starts on a known cell
+                body.cells = Cells::Unknown(BTreeSet::new());
+                body.nonzero.clear();
+                self.block(start + 1..end, &mut body);
+                *state = State::unknown();
+            }
+        }
+        state.cells.clear();
+        state.nonzero.remove(&0);
+    }
+
+    /// Return how much the code in `range` moves the memory pointer and the
+    /// offset of the cells it may write, or `None` if we can’t know
+    fn effect(&self, range: Range<usize>) -> Option<(i32, BTreeSet<i32>)> {
+        let code = &self.program.code;
+        let mut offset = 0;
+        let mut written = BTreeSet::new();
+        let mut i = range.start;
+        while i < range.end {
+            match code[i] {
+                '>' => offset += 1,
+                '<' => offset -= 1,
+                '+' | '-' | ',' | 'm' | '0' | '!' | '{' | '}' | '~' | '^' | '&' | '|' => {
+                    written.insert(offset);
+                }
+                '[' => {
+                    let end = self.program.brackets[i].unwrap();
+                    match self.effect(i + 1..end)? {
+                        (0, inner) => written.extend(inner.iter().map(|o| o + offset)),
+                        _ => return None,
+                    }
+                    i = end;
+                }
+                '(' => i = self.program.brackets[i].unwrap(),
+                ':' | 'Y' => return None,
+                _ => (),
+            }
+            i += 1;
+        }
+        Some((offset, written))
+    }
+}
@@ -0,0 +1,473 @@
+use crate::cell::{Bitwise, Overflow};
+use crate::parser::Program;
+use std::ops::Range;
+
+/// The instructions executed by the `Vm`.
+/// They are obtained by compiling the brainfuck source code, which fold
+/// the common patterns of brainfuck into a single instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    /// Add a value to the cell under the memory pointer (a run of `+` and `-`)
+    Add(i32),
+    /// Move the memory pointer (a run of `>` and `<`)
+    Move(i32),
+    /// Output the cell under the memory pointer (`.`)
+    Output,
+    /// Input a character in the cell under the memory pointer (`,`)
+    Input,
+    /// Jump to the instruction following the matching `JumpIfNotZero`
+    /// if the cell under the memory pointer is 0 (`[`)
+    JumpIfZero(usize),
+    /// Jump to the instruction following the matching `JumpIfZero`
+    /// if the cell under the memory pointer is not 0 (`]`)
+    JumpIfNotZero(usize),
+    /// Set the cell under the memory pointer to 0 (`[-]`, `[+]` or COW `OOO`)
+    Clear,
+    /// Add the cell under the memory pointer multiplied by `factor` to the
+    /// cell at `offset` from the memory pointer.
+    /// A multiply loop like `[->+>++<<]` is compiled to one `MulAdd` for each
+    /// cell it modifies followed by a `Clear`.
+    MulAdd { offset: i32, factor: i32 },
+    /// Move the memory pointer by `step` until it reaches a cell containing 0
+    /// (`[>]`, `[<<]`, …)
+    Scan(i32),
+    /// Do nothing, but stop the debugger (`#`)
+    Breakpoint,
+    /// Define the procedure starting at the next instruction with the number
+    /// in the cell under the memory pointer, then jump to `target` after the
+    /// end of the procedure (pbrain `(`)
+    Define(usize),
+    /// Return from the current procedure (pbrain `)`)
+    Return,
+    /// Call the procedure whose number is in the cell under the memory pointer
+    /// (pbrain `:`)
+    Call,
+    /// Start a new thread with its memory pointer on the next cell (Brainfork `Y`)
+    Fork,
+    /// Copy the cell under the memory pointer in the storage register
+    /// (Extended Brainfuck `$`)
+    Store,
+    /// Copy the storage register in the cell under the memory pointer
+    /// (Extended Brainfuck `!`)
+    Load,
+    /// Apply a bitwise operation to the cell under the memory pointer and the
+    /// storage register (Extended Brainfuck `{` `}` `~` `^` `&` `|`)
+    Bitwise(Bitwise),
+    /// End the program (Extended Brainfuck `@`)
+    End,
+    /// Input a character in the cell under the memory pointer if it’s 0,
+    /// output it otherwise (COW `Moo`)
+    InputOrOutput,
+}
+
+impl Instruction {
+    /// Return `true` if the instruction is not part of brainfuck itself
+    pub fn is_extension(&self) -> bool {
+        matches!(
+            self,
+            Define(_) | Return | Call | Fork | Store | Load | Bitwise(_) | End | InputOrOutput
+        )
+    }
+}
+
+use Instruction::*;
+
+/// The loops the compiler is allowed to fold into a few instructions
+#[derive(Debug, Clone, Copy)]
+pub struct Folding {
+    /// `[-]` and the multiply loops like `[->+<]`
+    pub decrement: bool,
+    /// `[+]`
+    pub increment: bool,
+    /// `[>]`, `[<<]`, …
+    pub scan: bool,
+    /// `+-` and `-+` into the `Add` of their sum, which is wrong if the
+    /// cell saturates or can’t overflow in between
+    pub mixed: bool,
+    /// `+++` and `>>>` into a single `Add` or `Move`
+    pub runs: bool,
+}
+
+impl Folding {
+    /// Fold all the loops which behave the same once folded with cells
+    /// `bounded` or not and the `overflow` semantic
+    pub fn new(bounded: bool, overflow: Overflow) -> Self {
+        Folding {
+            // an unbounded counter below 0 never reaches 0 by decrementing it
+            decrement: bounded,
+            // incrementing the counter only ends on 0 if it wraps around
+            increment: bounded && overflow == Overflow::Wrap,
+            scan: true,
+            mixed: !bounded || overflow == Overflow::Wrap,
+            runs: true,
+        }
+    }
+
+    /// Keep all the loops as they are written
+    pub fn none() -> Self {
+        Folding {
+            decrement: false,
+            increment: false,
+            scan: false,
+            mixed: false,
+            runs: true,
+        }
+    }
+
+    /// Compile each instruction of the source on its own, the Brainfork
+    /// threads take turns after each of them whatever the cells
+    pub fn unfolded() -> Self {
+        Folding {
+            runs: false,
+            ..Folding::none()
+        }
+    }
+}
+
+/// A compiled brainfuck program
+pub struct Compiled {
+    pub instructions: Vec<Instruction>,
+    /// For each instruction, the range of `Program::code` it was compiled from
+    pub sources: Vec<Range<usize>>,
+}
+
+impl Compiled {
+    fn push(&mut self, instruction: Instruction, source: Range<usize>) {
+        self.instructions.push(instruction);
+        self.sources.push(source);
+    }
+}
+
+/// Compile a parsed brainfuck program into a list of `Instruction`.
+/// Only the loops allowed by `folding` are folded.
+pub fn compile(program: &Program, folding: Folding) -> Compiled {
+    let source = &program.code;
+    let mut compiled = Compiled {
+        instructions: Vec::new(),
+        sources: Vec::new(),
+    };
+    // for each `[` or `(` compiled to a jump, the index of this instruction
+    let mut loop_start = vec![0; source.len()];
+    let mut i = 0;
+
+    while i < source.len() {
+        match source[i] {
+            '+' | '-' => {
+                let (value, len) = match (folding.runs, folding.mixed) {
+                    (false, _) => single(source[i], '+'),
+                    (true, true) => fold(&source[i..], '+', '-'),
+                    (true, false) => run(&source[i..], '+'),
+                };
+                if value != 0 {
+                    compiled.push(Add(value), i..i + len);
+                }
+                i += len;
+                continue;
+            }
+            // `<>` on the first cell goes outside of a classic tape, the
+            // moves are only folded while they go in the same direction
+            '>' | '<' => {
+                let (value, len) = match folding.runs {
+                    true => run(&source[i..], '>'),
+                    false => single(source[i], '>'),
+                };
+                compiled.push(Move(value), i..i + len);
+                i += len;
+                continue;
+            }
+            '.' => compiled.push(Output, i..i + 1),
+            ',' => compiled.push(Input, i..i + 1),
+            '#' => compiled.push(Breakpoint, i..i + 1),
+            ':' => compiled.push(Call, i..i + 1),
+            'Y' => compiled.push(Fork, i..i + 1),
+            '$' => compiled.push(Store, i..i + 1),
+            '!' => compiled.push(Load, i..i + 1),
+            '{' => compiled.push(Bitwise(Bitwise::ShiftLeft), i..i + 1),
+            '}' => compiled.push(Bitwise(Bitwise::ShiftRight), i..i + 1),
+            '~' => compiled.push(Bitwise(Bitwise::Not), i..i + 1),
+            '^' => compiled.push(Bitwise(Bitwise::Xor), i..i + 1),
+            '&' => compiled.push(Bitwise(Bitwise::And), i..i + 1),
+            '|' => compiled.push(Bitwise(Bitwise::Or), i..i + 1),
+            '@' => compiled.push(End, i..i + 1),
+            'm' => compiled.push(InputOrOutput, i..i + 1),
+            '0' => compiled.push(Clear, i..i + 1),
+            '[' => {
+                let end = program.brackets[i].unwrap();
+                if let Some(optimized) = simple_loop(&source[i + 1..end], folding) {
+                    for instruction in optimized {
+                        compiled.push(instruction, i..end + 1);
+                    }
+                    i = end + 1;
+                    continue;
+                }
+                loop_start[i] = compiled.instructions.len();
+                // the jump target is patched when we meet the matching `]`
+                compiled.push(JumpIfZero(0), i..i + 1);
+            }
+            ']' => {
+                let start = loop_start[program.brackets[i].unwrap()];
+                compiled.instructions[start] = JumpIfZero(compiled.instructions.len() + 1);
+                compiled.push(JumpIfNotZero(start + 1), i..i + 1);
+            }
+            '(' => {
+                loop_start[i] = compiled.instructions.len();
+                compiled.push(Define(0), i..i + 1);
+            }
+            ')' => {
+                let start = loop_start[program.brackets[i].unwrap()];
+                compiled.instructions[start] = Define(compiled.instructions.len() + 1);
+                compiled.push(Return, i..i + 1);
+            }
+            _ => unreachable!(),
+        }
+        i += 1;
+    }
+
+    compiled
+}
+
+/// Fold the run of `inc` and `dec` at the start of `source`.
+/// Return the resulting value and the number of characters consumed.
+pub fn fold(source: &[char], inc: char, dec: char) -> (i32, usize) {
+    let mut value = 0;
+    let mut len = 0;
+    for &c in source {
+        if c == inc {
+            value += 1;
+        } else if c == dec {
+            value -= 1;
+        } else {
+            break;
+        }
+        len += 1;
+    }
+    (value, len)
+}
+
+/// Fold the repetitions of the first character of `source`, which is `inc`
+/// or its opposite. Return the resulting value and the number of characters
+/// consumed.
+pub fn run(source: &[char], inc: char) -> (i32, usize) {
+    let len = source.iter().take_while(|&&c| c == source[0]).count();
+    match source[0] == inc {
+        true => (len as i32, len),
+        false => (-(len as i32), len),
+    }
+}
+
+/// Return the value of `c`, which is `inc` or its opposite, and the one
+/// character consumed
+fn single(c: char, inc: char) -> (i32, usize) {
+    match c == inc {
+        true => (1, 1),
+        false => (-1, 1),
+    }
+}
+
+/// Try to compile the loop with this body into a few instructions
+/// without any jump.
+fn simple_loop(body: &[char], folding: Folding) -> Option<Vec<Instruction>> {
+    if body.is_empty() || body.iter().any(|c| !"+-<>".contains(*c)) {
+        return None;
+    }
+
+    // `[>]`, `[<<]`
+    if body.iter().all(|&c| c == '>') || body.iter().all(|&c| c == '<') {
+        return match folding.scan {
+            true => Some(vec![Scan(fold(body, '>', '<').0)]),
+            false => None,
+        };
+    }
+
+    // `[-]`, `[->+<]`, `[->++>>+<<<]`
+    let mut offset = 0;
+    // the farthest cells the loop goes to, it must modify them so the
+    // folded loop goes outside of the tape when the loop would
+    let (mut min, mut max) = (0, 0);
+    // the value added to each cell by one iteration of the loop
+    let mut changes: Vec<(i32, i32)> = Vec::new();
+    // a cell is incremented and decremented by the same iteration
+    let mut mixed = false;
+    for &c in body {
+        match c {
+            '>' => {
+                offset += 1;
+                max = max.max(offset);
+            }
+            '<' => {
+                offset -= 1;
+                min = min.min(offset);
+            }
+            '+' | '-' => {
+                let value = if c == '+' { 1 } else { -1 };
+                match changes.iter_mut().find(|(o, _)| *o == offset) {
+                    Some((_, v)) => {
+                        mixed |= *v != 0 && v.signum() != value;
+                        *v += value;
+                    }
+                    None => changes.push((offset, value)),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+    // the loop must come back on its counter and decrement it by one at each
+    // iteration; with wrapping cells `[+]` also end up clearing the counter
+    let counter = changes.iter().find(|(o, _)| *o == 0).map(|(_, v)| *v);
+    let decrement = counter == Some(-1) && folding.decrement;
+    let increment = counter == Some(1) && changes.len() == 1 && folding.increment;
+    let modified = |o: i32| changes.iter().any(|&(offset, v)| offset == o && v != 0);
+    if offset != 0 || !(decrement || increment) || !modified(min) || !modified(max) {
+        return None;
+    }
+    // `[->+-<]` can saturate or overflow in the middle of an iteration
+    if mixed && !folding.mixed {
+        return None;
+    }
+
+    let mut instructions: Vec<Instruction> = changes
+        .into_iter()
+        .filter(|&(offset, factor)| offset != 0 && factor != 0)
+        .map(|(offset, factor)| MulAdd { offset, factor })
+        .collect();
+    instructions.push(Clear);
+    Some(instructions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{self, Extensions};
+
+    fn compiled(source: &str, folding: Folding) -> Vec<Instruction> {
+        let source: Vec<char> = source.chars().collect();
+        let program = parser::parse(&parser::positions(&source), Extensions::default()).unwrap();
+        compile(&program, folding).instructions
+    }
+
+    /// Compile with the folding of wrapping 8-bit cells
+    fn folded(source: &str) -> Vec<Instruction> {
+        compiled(source, Folding::new(true, Overflow::Wrap))
+    }
+
+    #[test]
+    fn runs() {
+        assert_eq!(folded("+++--"), [Add(1)]);
+        assert_eq!(folded(">>><"), [Move(3), Move(-1)]);
+        assert_eq!(folded("+-"), []);
+        assert_eq!(folded("+ comment +.,"), [Add(2), Output, Input]);
+    }
+
+    #[test]
+    fn clear() {
+        assert_eq!(folded("[-]"), [Clear]);
+        assert_eq!(folded("[+]"), [Clear]);
+        assert_eq!(folded(">[-]<"), [Move(1), Clear, Move(-1)]);
+    }
+
+    #[test]
+    fn scan() {
+        assert_eq!(folded("[>]"), [Scan(1)]);
+        assert_eq!(folded("[<<]"), [Scan(-2)]);
+    }
+
+    #[test]
+    fn multiply() {
+        assert_eq!(
+            folded("[->++<]"),
+            [
+                MulAdd {
+                    offset: 1,
+                    factor: 2
+                },
+                Clear
+            ]
+        );
+        assert_eq!(
+            folded("[>+<<---->-]"),
+            [
+                MulAdd {
+                    offset: 1,
+                    factor: 1
+                },
+                MulAdd {
+                    offset: -1,
+                    factor: -4
+                },
+                Clear
+            ]
+        );
+        // the loop doesn’t come back on its counter
+        assert_eq!(
+            folded("[->+]"),
+            [JumpIfZero(5), Add(-1), Move(1), Add(1), JumpIfNotZero(1)]
+        );
+    }
+
+    #[test]
+    fn jumps() {
+        assert_eq!(
+            folded("+[>[.]<-]"),
+            [
+                Add(1),
+                JumpIfZero(9),
+                Move(1),
+                JumpIfZero(6),
+                Output,
+                JumpIfNotZero(4),
+                Move(-1),
+                Add(-1),
+                JumpIfNotZero(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn saturating_cells() {
+        let saturate = Folding::new(true, Overflow::Saturate);
+        assert_eq!(compiled("++-", saturate), [Add(2), Add(-1)]);
+        assert_eq!(compiled("[-]", saturate), [Clear]);
+        // `[+]` never reaches 0 and `[->++-<]` can saturate in the middle
+        assert_eq!(
+            compiled("[+]", saturate),
+            [JumpIfZero(3), Add(1), JumpIfNotZero(1)]
+        );
+        assert_eq!(compiled("[->++-<]", saturate).len(), 7);
+        assert_eq!(
+            folded("[->++-<]"),
+            [
+                MulAdd {
+                    offset: 1,
+                    factor: 1
+                },
+                Clear
+            ]
+        );
+    }
+
+    #[test]
+    fn unbounded_cells() {
+        let bignum = Folding::new(false, Overflow::Wrap);
+        assert_eq!(compiled("++-", bignum), [Add(1)]);
+        // a counter below 0 never reaches 0
+        assert_eq!(
+            compiled("[-]", bignum),
+            [JumpIfZero(3), Add(-1), JumpIfNotZero(1)]
+        );
+        assert_eq!(compiled("[->+<]", bignum).len(), 6);
+        assert_eq!(compiled("[>]", bignum), [Scan(1)]);
+    }
+
+    #[test]
+    fn nothing_folded() {
+        let none = Folding::none();
+        assert_eq!(compiled("++-", none), [Add(2), Add(-1)]);
+        assert_eq!(compiled("[-]", none).len(), 3);
+        assert_eq!(
+            compiled("[>]", none),
+            [JumpIfZero(3), Move(1), JumpIfNotZero(1)]
+        );
+        let unfolded = Folding::unfolded();
+        assert_eq!(compiled("++>", unfolded), [Add(1), Add(1), Move(1)]);
+    }
+}
@@ -0,0 +1,289 @@
+use crate::cell::Cell;
+use crate::compiler::{Compiled, Instruction};
+use crate::parser::{Position, Program};
+use crate::vm::Vm;
+use std::collections::BTreeSet;
+use std::io::{BufRead, Read, Write};
+
+pub const HELP: &str = "\
+commands:
+\tstep [n]            (s) execute n instructions, 1 by default
+\tcontinue            (c) execute until a breakpoint or the end of the program
+\tfinish              (f) execute until the current loop exits
+\treverse-step [n]    (rs) undo n instructions, 1 by default
+\treverse-continue    (rc) undo instructions until a breakpoint or the start of the history
+\tbreak [line:column] (b) add a breakpoint, or list them without position
+\tdelete line:column  (d) remove a breakpoint
+\ttape [radius]       (t) show the cells around the memory pointer, 5 by default
+\tset cell value          set the value of a cell
+\twhere               (w) show the next instruction
+\thelp                (h) show this message
+\tquit                (q) stop the debugger
+the program also stops on each `#`, the output of the program can’t be undone";
+
+/// The number of instructions which can be undone
+const HISTORY: usize = 1_000_000;
+
+/// An interactive debugger executing the program step by step with the `Vm`
+pub struct Debugger<'a, C: Cell, R: Read, W: Write> {
+    vm: Vm<'a, C, R, W>,
+    program: &'a Program,
+    compiled: &'a Compiled,
+    /// the instructions on which the execution stops
+    breakpoints: BTreeSet<usize>,
+}
+
+impl<'a, C: Cell, R: Read, W: Write> Debugger<'a, C, R, W> {
+    pub fn new(mut vm: Vm<'a, C, R, W>, program: &'a Program, compiled: &'a Compiled) -> Self {
+        vm.record(HISTORY);
+        Debugger {
+            vm,
+            program,
+            compiled,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    /// Execute the commands read from `commands` until the user quit
+    pub fn run(&mut self, commands: impl BufRead) {
+        self.location();
+        self.prompt();
+        for line in commands.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                [] => (),
+                ["s"] | ["step"] => self.step(1),
+                ["s", n] | ["step", n] => match n.parse() {
+                    Ok(n) => self.step(n),
+                    Err(_) => println!("invalid number of steps `{}`", n),
+                },
+                ["c"] | ["continue"] => self.execute(|_| false),
+                ["f"] | ["finish"] => self.finish(),
+                ["rs"] | ["reverse-step"] => self.reverse_step(1),
+                ["rs", n] | ["reverse-step", n] => match n.parse() {
+                    Ok(n) => self.reverse_step(n),
+                    Err(_) => println!("invalid number of steps `{}`", n),
+                },
+                ["rc"] | ["reverse-continue"] => self.reverse_continue(),
+                ["b"] | ["break"] => self.list_breakpoints(),
+                ["b", pos] | ["break", pos] => {
+                    if let Some(i) = self.instruction_at(pos) {
+                        self.breakpoints.insert(i);
+                        println!("breakpoint at {}", self.position(i));
+                    }
+                }
+                ["d", pos] | ["delete", pos] => {
+                    if let Some(i) = self.instruction_at(pos) {
+                        if !self.breakpoints.remove(&i) {
+                            println!("there is no breakpoint at {}", self.position(i));
+                        }
+                    }
+                }
+                ["t"] | ["tape"] => self.tape(5),
+                ["t", radius] | ["tape", radius] => match radius.parse() {
+                    Ok(radius) => self.tape(radius),
+                    Err(_) => println!("invalid radius `{}`", radius),
+                },
+                ["set", cell, value] => self.set(cell, value),
+                ["w"] | ["where"] => self.location(),
+                ["h"] | ["help"] => println!("{}", HELP),
+                ["q"] | ["quit"] => return,
+                _ => println!("unknown command, type `help` to see the commands"),
+            }
+            self.prompt();
+        }
+    }
+
+    fn prompt(&self) {
+        print!("(bfdb) ");
+        std::io::stdout().flush().unwrap();
+    }
+
+    /// Execute `n` instructions
+    fn step(&mut self, n: usize) {
+        let mut executed = 0;
+        self.execute(|_| {
+            executed += 1;
+            executed > n
+        });
+    }
+
+    /// Execute instructions until `stop` returns `true`, we reach a
+    /// breakpoint or the program ends.
+    /// `stop` is called with the next instruction pointer before each instruction.
+    fn execute(&mut self, mut stop: impl FnMut(usize) -> bool) {
+        let mut first = true;
+        while !self.vm.finished() {
+            let ip = self.vm.instruction_pointer();
+            if stop(ip) {
+                break;
+            }
+            // we don’t want to stay stuck on the breakpoint we are stopped on
+            if !first
+                && (self.breakpoints.contains(&ip)
+                    || self.compiled.instructions[ip] == Instruction::Breakpoint)
+            {
+                break;
+            }
+            first = false;
+            if let Err(e) = self.vm.cycle() {
+                self.flush();
+                println!("runtime error: {}", e);
+                break;
+            }
+        }
+        self.flush();
+        self.location();
+    }
+
+    /// Execute until we exit the innermost loop containing the next instruction
+    fn finish(&mut self) {
+        let ip = self.vm.instruction_pointer();
+        // the innermost loop is the closest `[` before us jumping after us
+        let end = self.compiled.instructions[..ip.min(self.compiled.instructions.len())]
+            .iter()
+            .rev()
+            .find_map(|instruction| match *instruction {
+                Instruction::JumpIfZero(end) if end > ip => Some(end),
+                _ => None,
+            });
+        match end {
+            Some(end) => self.execute(|ip| ip == end),
+            None => println!("we are not in a loop"),
+        }
+    }
+
+    /// Undo `n` instructions
+    fn reverse_step(&mut self, n: usize) {
+        for _ in 0..n {
+            if !self.vm.undo() {
+                println!("there is nothing to undo");
+                break;
+            }
+        }
+        self.location();
+    }
+
+    /// Undo instructions until we go back to a breakpoint or to the oldest
+    /// instruction we remember
+    fn reverse_continue(&mut self) {
+        while self.vm.undo() {
+            let ip = self.vm.instruction_pointer();
+            if self.breakpoints.contains(&ip)
+                || self.compiled.instructions[ip] == Instruction::Breakpoint
+            {
+                return self.location();
+            }
+        }
+        println!("there is nothing more to undo");
+        self.location();
+    }
+
+    fn list_breakpoints(&self) {
+        if self.breakpoints.is_empty() {
+            println!("there is no breakpoint");
+        }
+        for &i in &self.breakpoints {
+            println!("breakpoint at {}", self.position(i));
+        }
+    }
+
+    /// Show the cells in `radius` around the memory pointer
+    fn tape(&self, radius: i32) {
+        let mp = self.vm.memory_pointer();
+        let mut indexes = String::from("cell ");
+        let mut values = String::from("value");
+        for i in mp.saturating_sub(radius)..=mp.saturating_add(radius) {
+            if let Some(cell) = self.vm.cell(i) {
+                let (index, value) = if i == mp {
+                    (format!("[{}]", i), format!("[{}]", cell))
+                } else {
+                    (i.to_string(), cell.to_string())
+                };
+                let width = index.len().max(value.len());
+                indexes.push_str(&format!(" {:>w$}", index, w = width));
+                values.push_str(&format!(" {:>w$}", value, w = width));
+            }
+        }
+        println!("{}\n{}", indexes, values);
+    }
+
+    fn set(&mut self, cell: &str, value: &str) {
+        let cell = match cell.parse() {
+            Ok(cell) => cell,
+            Err(_) => return println!("invalid cell `{}`", cell),
+        };
+        let value = match value.parse() {
+            Ok(value) => value,
+            Err(_) => return println!("invalid value `{}`", value),
+        };
+        if let Err(e) = self.vm.set_cell(cell, value) {
+            println!("{}", e);
+        }
+    }
+
+    /// Show the next instruction to execute
+    fn location(&self) {
+        let ip = self.vm.instruction_pointer();
+        if self.vm.finished() {
+            println!("the program is finished");
+            return;
+        }
+        let source: String = self.program.code[self.compiled.sources[ip].clone()]
+            .iter()
+            .collect();
+        let mp = self.vm.memory_pointer();
+        println!(
+            "at {}: {}\t(memory pointer: {}, cell: {})",
+            self.position(ip),
+            source,
+            mp,
+            self.vm.cell(mp).cloned().unwrap_or_default(),
+        );
+    }
+
+    /// The position in the source code of an instruction
+    fn position(&self, instruction: usize) -> Position {
+        self.program.positions[self.compiled.sources[instruction].start]
+    }
+
+    /// Return the first instruction compiled from the source code after the
+    /// position `line:column`
+    fn instruction_at(&self, pos: &str) -> Option<usize> {
+        let mut parts = pos.splitn(2, ':').map(str::parse::<usize>);
+        let pos = match (parts.next(), parts.next()) {
+            (Some(Ok(line)), None) => Position { line, column: 1 },
+            (Some(Ok(line)), Some(Ok(column))) => Position { line, column },
+            _ => {
+                println!("invalid position `{}`, expected `line:column`", pos);
+                return None;
+            }
+        };
+        let code = self
+            .program
+            .positions
+            .iter()
+            .position(|p| (p.line, p.column) >= (pos.line, pos.column));
+        let instruction = code.and_then(|code| {
+            self.compiled
+                .sources
+                .iter()
+                .position(|source| source.end > code)
+        });
+        if instruction.is_none() {
+            println!("there is no instruction after {}", pos);
+        }
+        instruction
+    }
+
+    /// Write the output of the program before printing anything
+    fn flush(&mut self) {
+        if let Err(e) = self.vm.flush() {
+            println!("{}", e);
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use crate::parser::{ParseError, Position};
+use std::path::Path;
+
+/// A language which is brainfuck with its instructions replaced by other tokens
+pub struct Dialect {
+    /// Each token with the brainfuck instructions it stands for, or `None` if
+    /// it has no equivalent in brainfuck. The longest tokens come first.
+    tokens: Vec<(Vec<char>, Option<String>)>,
+    /// The COW extension is needed for `m`
+    pub cow: bool,
+}
+
+const OOK: [(&str, &str); 8] = [
+    ("Ook. Ook?", ">"),
+    ("Ook? Ook.", "<"),
+    ("Ook. Ook.", "+"),
+    ("Ook! Ook!", "-"),
+    ("Ook! Ook.", "."),
+    ("Ook. Ook!", ","),
+    ("Ook! Ook?", "["),
+    ("Ook? Ook!", "]"),
+];
+
+/// The COW instructions, `mOO`, `MMM`, `OOM` and `oom` are not supported.
+/// `Moo` reads a character if the cell is 0 and prints it otherwise, it
+/// becomes the `m` of the COW extension. `OOO` sets the cell to 0 even when
+/// it’s negative, it becomes the `0` of the COW extension.
+const COW: [(&str, Option<&str>); 12] = [
+    ("moo", Some("]")),
+    ("mOo", Some("<")),
+    ("moO", Some(">")),
+    ("mOO", None),
+    ("Moo", Some("m")),
+    ("MOo", Some("-")),
+    ("MoO", Some("+")),
+    ("MOO", Some("[")),
+    ("OOO", Some("0")),
+    ("MMM", None),
+    ("OOM", None),
+    ("oom", None),
+];
+
+impl Dialect {
+    fn new<'a>(tokens: impl IntoIterator<Item = (&'a str, Option<&'a str>)>) -> Self {
+        let mut tokens: Vec<_> = tokens
+            .into_iter()
+            .map(|(token, code)| (token.chars().collect::<Vec<_>>(), code.map(String::from)))
+            .collect();
+        // `ab` must be tried before `a`
+        tokens.sort_by_key(|(token, _)| std::cmp::Reverse(token.len()));
+        Dialect { tokens, cow: false }
+    }
+
+    pub fn ook() -> Self {
+        Dialect::new(OOK.iter().map(|&(token, code)| (token, Some(code))))
+    }
+
+    /// Blub is Ook! for the fishes
+    pub fn blub() -> Self {
+        let tokens: Vec<_> = OOK
+            .iter()
+            .map(|&(token, code)| (token.replace("Ook", "Blub"), code))
+            .collect();
+        Dialect::new(
+            tokens
+                .iter()
+                .map(|(token, code)| (token.as_str(), Some(*code))),
+        )
+    }
+
+    pub fn cow() -> Self {
+        Dialect {
+            cow: true,
+            ..Dialect::new(COW.iter().cloned())
+        }
+    }
+
+    /// Read a token map, each line is `instructions = token` and the lines
+    /// starting with `#` are comments:
+    /// ```text
+    /// # brainfuck with its instructions in french
+    /// + = plus
+    /// - = moins
+    /// ```
+    pub fn from_config(config: &str) -> Result<Self, String> {
+        let mut tokens = Vec::new();
+        for (i, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=').map(str::trim);
+            match (parts.next(), parts.next()) {
+                (Some(code), Some(token)) if !code.is_empty() && !token.is_empty() => {
+                    tokens.push((token, Some(code)))
+                }
+                _ => {
+                    return Err(format!(
+                        "invalid token map at line {}, expected `instructions = token`",
+                        i + 1
+                    ))
+                }
+            }
+        }
+        Ok(Dialect::new(tokens))
+    }
+
+    /// Return the dialect asked by the user, a name or the path of a token
+    /// map, or the dialect of the file extension.
+    /// `None` is brainfuck itself.
+    pub fn select(choice: Option<&str>, file: &str) -> Result<Option<Self>, String> {
+        let extension = Path::new(file).extension().and_then(|e| e.to_str());
+        match choice.or(extension) {
+            Some("ook") => Ok(Some(Dialect::ook())),
+            Some("blub") => Ok(Some(Dialect::blub())),
+            Some("cow") => Ok(Some(Dialect::cow())),
+            Some(path) if choice.is_some() && path != "brainfuck" => {
+                let config = std::fs::read_to_string(path)
+                    .map_err(|e| format!("can’t read the token map `{}`: {}", path, e))?;
+                Dialect::from_config(&config).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Translate the source code to brainfuck, every instruction keeps the
+    /// position of its token. Everything that is not a token is a comment.
+    pub fn translate(
+        &self,
+        source: &[(char, Position)],
+    ) -> Result<Vec<(char, Position)>, ParseError> {
+        let chars: Vec<char> = source.iter().map(|&(c, _)| c).collect();
+        let mut code = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            let token = self
+                .tokens
+                .iter()
+                .find_map(|(token, code)| Some((token, code, matches(&chars[i..], token)?)));
+            match token {
+                Some((_, Some(instructions), len)) => {
+                    let pos = source[i].1;
+                    code.extend(instructions.chars().map(|c| (c, pos)));
+                    i += len;
+                }
+                Some((token, None, _)) => {
+                    return Err(ParseError::Untranslatable(
+                        token.iter().collect(),
+                        source[i].1,
+                    ))
+                }
+                None => i += 1,
+            }
+        }
+        Ok(code)
+    }
+}
+
+/// Return the length of `token` at the start of `source`, if it’s there.
+/// A space in the token matches any whitespace, `Ook.\nOok?` is a token.
+fn matches(source: &[char], token: &[char]) -> Option<usize> {
+    let (mut i, mut t) = (0, 0);
+    while t < token.len() {
+        if token[t].is_whitespace() {
+            while t < token.len() && token[t].is_whitespace() {
+                t += 1;
+            }
+            let start = i;
+            while i < source.len() && source[i].is_whitespace() {
+                i += 1;
+            }
+            if i == start {
+                return None;
+            }
+        } else if source.get(i) == Some(&token[t]) {
+            i += 1;
+            t += 1;
+        } else {
+            return None;
+        }
+    }
+    Some(i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    fn translated(dialect: &Dialect, source: &str) -> Result<String, ParseError> {
+        let source: Vec<char> = source.chars().collect();
+        let code = dialect.translate(&parser::positions(&source))?;
+        Ok(code.iter().map(|&(c, _)| c).collect())
+    }
+
+    #[test]
+    fn ook() {
+        let source = "Ook. Ook. Ook! Ook?\nOok. Ook? Ook! Ook. Ook? Ook!";
+        assert_eq!(translated(&Dialect::ook(), source).unwrap(), "+[>.]");
+    }
+
+    #[test]
+    fn cow() {
+        let cow = Dialect::cow();
+        assert_eq!(translated(&cow, "MoO MOO MOo moo Moo").unwrap(), "+[-]m");
+        assert_eq!(translated(&cow, "MOo OOO").unwrap(), "-0");
+        for token in ["mOO", "MMM", "OOM", "oom"] {
+            assert!(matches!(
+                translated(&cow, token),
+                Err(ParseError::Untranslatable(t, _)) if t == token
+            ));
+        }
+    }
+}
@@ -0,0 +1,377 @@
+use crate::compiler::{self, Folding};
+use crate::parser::{Extensions, Program};
+use crate::tape::{self, Boundary};
+use crate::vm::Config;
+
+const INDENT: usize = 4;
+/// The longest loop kept on a single line, like `[->+<]`
+const INLINE_LOOP: usize = 8;
+
+/// A line of the formatted program
+#[derive(Default)]
+struct Line {
+    /// the comment before the code, like a line number
+    prefix: String,
+    /// the loop depth of the code
+    depth: usize,
+    code: String,
+    /// the comment after the code
+    suffix: String,
+    /// the column of the comment after the code in the source
+    column: usize,
+    /// a comment alone on its line which continues the comment after the
+    /// code of the line above
+    continued: bool,
+}
+
+/// Indent the code by loop depth, the comments stay on the line of the code
+/// they are attached to and the comments after the code are aligned.
+/// A comment alone on its line is indented like the code, unless it continues
+/// the comment of the line above: it starts after the code of this line in the
+/// source, maybe after a line number. The code is split on each loop, except the short loops like `[-]`, and the
+/// code after a comment goes on its own line.
+pub fn format(source: &[char], extensions: Extensions) -> String {
+    let source: String = source.iter().collect();
+    let mut lines: Vec<Line> = Vec::new();
+    let mut depth = 0;
+
+    for line in source.lines() {
+        let indent = line.chars().take_while(|c| c.is_whitespace()).count();
+        let line = line.trim();
+        if line.is_empty() {
+            // keep one blank line between two paragraphs
+            if lines.last().is_some_and(|l| !l.is_blank()) {
+                lines.push(Line::default());
+            }
+            continue;
+        }
+        let Some(start) = line.find(|c| extensions.contains(c)) else {
+            // the column of the comment, or of its second word after a line
+            // number separated by more than a space
+            let first = line.find(char::is_whitespace).unwrap_or(line.len());
+            let spaces = line[first..]
+                .chars()
+                .take_while(|c| c.is_whitespace())
+                .count();
+            let second = indent + line[..first].chars().count() + spaces;
+            let above = lines
+                .last()
+                .filter(|l| !l.suffix.is_empty() && (!l.code.is_empty() || l.continued));
+            let continued =
+                above.is_some_and(|l| indent >= l.column || (spaces > 1 && second >= l.column));
+            lines.push(Line {
+                depth,
+                suffix: line.to_string(),
+                column: above.map_or(0, |l| l.column),
+                continued,
+                ..Line::default()
+            });
+            continue;
+        };
+        let mut prefix = line[..start].trim();
+        let mut rest = &line[start..];
+        while !rest.is_empty() {
+            // the code goes until the next comment, which goes until the
+            // next code
+            let end = rest
+                .find(|c: char| !extensions.contains(c) && !c.is_whitespace())
+                .unwrap_or(rest.len());
+            let next = rest[end..]
+                .find(|c| extensions.contains(c))
+                .map_or(rest.len(), |next| end + next);
+            // the code contains an instruction so at least one line is pushed
+            let comment = &rest[end..next];
+            let column = line.len() - rest.len() + end + comment.len() - comment.trim_start().len();
+            let first = lines.len();
+            split(&rest[..end], &mut depth, &mut lines);
+            lines[first].prefix = prefix.to_string();
+            let last = lines.last_mut().unwrap();
+            last.suffix = comment.trim().to_string();
+            last.column = indent + line[..column].chars().count();
+            prefix = "";
+            rest = &rest[next..];
+        }
+    }
+    while lines.last().is_some_and(Line::is_blank) {
+        lines.pop();
+    }
+
+    // the code starts after the widest prefix and the comments after the
+    // widest code
+    let prefix = lines
+        .iter()
+        .filter(|l| !l.code.is_empty())
+        .map(|l| l.prefix.chars().count())
+        .max()
+        .unwrap_or(0);
+    let margin = if prefix == 0 { 0 } else { prefix + 1 };
+    let comments = lines
+        .iter()
+        .filter(|l| !l.code.is_empty() && !l.suffix.is_empty())
+        .map(|l| l.depth * INDENT + l.code.chars().count() + 2)
+        .max()
+        .unwrap_or(0);
+
+    let mut formatted = String::new();
+    for line in &lines {
+        let indent = match line.continued {
+            true => comments,
+            false => line.depth * INDENT,
+        };
+        let mut text = if line.code.is_empty() && margin > 0 {
+            // a comment alone on its line starts with its prefix too if its
+            // first word fits in the margin
+            let (prefix, text) = match line.suffix.split_once(' ') {
+                Some((prefix, text)) if prefix.chars().count() < margin => (prefix, text.trim()),
+                _ if line.suffix.chars().count() < margin => (line.suffix.as_str(), ""),
+                _ => ("", line.suffix.as_str()),
+            };
+            format!("{:m$}{:i$}{}", prefix, "", text, m = margin, i = indent)
+        } else if line.code.is_empty() {
+            format!("{:i$}{}", "", line.suffix, i = indent)
+        } else {
+            format!(
+                "{:p$}{:i$}{}",
+                line.prefix,
+                "",
+                line.code,
+                p = margin,
+                i = line.depth * INDENT
+            )
+        };
+        if !line.code.is_empty() && !line.suffix.is_empty() {
+            let width = text.chars().count();
+            text.push_str(&" ".repeat((margin + comments).saturating_sub(width)));
+            text.push_str(&line.suffix);
+        }
+        formatted.push_str(text.trim_end());
+        formatted.push('\n');
+    }
+    formatted
+}
+
+impl Line {
+    fn is_blank(&self) -> bool {
+        self.prefix.is_empty() && self.code.is_empty() && self.suffix.is_empty()
+    }
+}
+
+/// Split a line of code without comments on each loop
+fn split(code: &str, depth: &mut usize, lines: &mut Vec<Line>) {
+    let code: Vec<char> = code.chars().collect();
+    let mut current = String::new();
+    // move the code of the current line in `lines`
+    fn flush(current: &mut String, depth: usize, lines: &mut Vec<Line>) {
+        let code = current.trim();
+        if !code.is_empty() {
+            lines.push(Line {
+                depth,
+                code: code.to_string(),
+                ..Line::default()
+            });
+        }
+        current.clear();
+    }
+
+    let mut i = 0;
+    while i < code.len() {
+        match code[i] {
+            '[' => match short_loop(&code[i..]) {
+                Some(len) => {
+                    current.extend(&code[i..i + len]);
+                    i += len;
+                    continue;
+                }
+                None => {
+                    flush(&mut current, *depth, lines);
+                    current.push('[');
+                    flush(&mut current, *depth, lines);
+                    *depth += 1;
+                }
+            },
+            ']' => {
+                flush(&mut current, *depth, lines);
+                *depth = depth.saturating_sub(1);
+                current.push(']');
+                flush(&mut current, *depth, lines);
+            }
+            c => current.push(c),
+        }
+        i += 1;
+    }
+    flush(&mut current, *depth, lines);
+}
+
+/// Return the length of the loop at the start of `code` if it’s short and
+/// without any nested loop
+fn short_loop(code: &[char]) -> Option<usize> {
+    let end = code.iter().skip(1).position(|&c| c == '[' || c == ']')? + 1;
+    match code[end] {
+        ']' if end < INLINE_LOOP => Some(end + 1),
+        _ => None,
+    }
+}
+
+/// Return the instructions of the program without anything else, each run of
+/// `+` and `-` is replaced by the instructions doing the same thing when the
+/// cells with this `config` can’t saturate nor fail in between: `+-+` becomes
+/// `+`. The runs of `>` and `<` are replaced too when the memory pointer can’t
+/// go outside of the tape in between. Once `><` is removed from `+><-` its `+`
+/// and `-` are folded too.
+pub fn minify(program: &Program, config: &Config, bounded: bool) -> String {
+    let mut code = program.code.clone();
+    loop {
+        let minified = fold_runs(&code, config, bounded);
+        if minified == code {
+            return code.into_iter().collect();
+        }
+        code = minified;
+    }
+}
+
+/// Replace each run of `code` once, see `minify`
+fn fold_runs(code: &[char], config: &Config, bounded: bool) -> Vec<char> {
+    let mixed = Folding::new(bounded, config.overflow).mixed;
+    // the memory pointer, until we can’t know where it is
+    let mut pointer = Some(0);
+    let mut minified = Vec::new();
+    let mut i = 0;
+    while i < code.len() {
+        let (value, len, inc, dec) = match code[i] {
+            '+' | '-' if mixed => {
+                let (value, len) = compiler::fold(&code[i..], '+', '-');
+                (value, len, '+', '-')
+            }
+            '+' | '-' => {
+                let (value, len) = compiler::run(&code[i..], '+');
+                (value, len, '+', '-')
+            }
+            '>' | '<' => {
+                let (value, len) = moves(&code[i..], pointer, config);
+                pointer = pointer.map(|p| p + value as i64);
+                (value, len, '>', '<')
+            }
+            c => {
+                // the loops and the procedures run any number of times and a
+                // new thread starts on the next cell
+                if "[]():Y".contains(c) {
+                    pointer = None;
+                }
+                minified.push(c);
+                i += 1;
+                continue;
+            }
+        };
+        let c = if value > 0 { inc } else { dec };
+        minified.extend(std::iter::repeat_n(c, value.unsigned_abs() as usize));
+        i += len;
+    }
+    minified
+}
+
+/// Fold the run of `>` and `<` at the start of `code` if the memory pointer
+/// can’t go outside of the tape in between: always on a wrapping tape,
+/// otherwise only if we know it’s at `pointer`. The moves are only folded
+/// while they go in the same direction when it could.
+/// Return the resulting move and the number of characters consumed.
+fn moves(code: &[char], pointer: Option<i64>, config: &Config) -> (i32, usize) {
+    let (value, len) = compiler::fold(code, '>', '<');
+    // the farthest cells the moves go to
+    let (mut offset, mut min, mut max) = (0, 0, 0);
+    for &c in &code[..len] {
+        offset += if c == '>' { 1 } else { -1 };
+        min = min.min(offset);
+        max = max.max(offset);
+    }
+    let inside = |offset: i64| {
+        pointer.is_some_and(|p| tape::inside(config.boundary, config.tape_size, p + offset))
+    };
+    match config.boundary == Boundary::Wrap || (inside(min) && inside(max)) {
+        true => (value, len),
+        false => compiler::run(code, '>'),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::Overflow;
+    use crate::parser;
+    use crate::vm::Eof;
+
+    fn fmt(source: &str) -> String {
+        let source: Vec<char> = source.chars().collect();
+        format(&source, Extensions::default())
+    }
+
+    const CONFIG: Config = Config {
+        overflow: Overflow::Wrap,
+        eof: Eof::Zero,
+        boundary: Boundary::Classic,
+        tape_size: 16,
+    };
+
+    fn minified(source: &str, config: Config) -> String {
+        let source: Vec<char> = source.chars().collect();
+        let program = parser::parse(&parser::positions(&source), Extensions::default()).unwrap();
+        minify(&program, &config, true)
+    }
+
+    #[test]
+    fn code_after_a_comment() {
+        // the loop closes after the comment, the next lines are not indented
+        assert_eq!(
+            fmt("++++[>+++ add three then -<-]\n>.\n[-]\n"),
+            "++++\n[\n    >+++  add three then\n    -<-\n]\n>.\n[-]\n"
+        );
+        assert_eq!(
+            fmt("+[>++++++++[-]<-]lala>.\n"),
+            "+\n[\n    >++++++++[-]<-\n]  lala\n>.\n"
+        );
+    }
+
+    #[test]
+    fn comment_alone_on_its_line() {
+        // a paragraph is indented like the code
+        assert_eq!(
+            fmt("+[\n>+ add one\n\nthe cell is one\n<-]\n"),
+            "+\n[\n    >+  add one\n\n    the cell is one\n    <-\n]\n"
+        );
+        // the rest of a comment after the code stays aligned with it
+        assert_eq!(
+            fmt("+[\n>+   add one\n     to the next cell\n<-]\n"),
+            "+\n[\n    >+  add one\n        to the next cell\n    <-\n]\n"
+        );
+        // even after a line number
+        assert_eq!(
+            fmt("1 +  add one\n2      to the cell\n3 the end\n"),
+            "1 +  add one\n2    to the cell\n3 the end\n"
+        );
+    }
+
+    #[test]
+    fn minify_cancels_opposite_moves() {
+        assert_eq!(minified("+><-", CONFIG), "");
+        assert_eq!(minified(">><", CONFIG), ">");
+        // `<>` goes outside of a classic tape on the first cell
+        assert_eq!(minified("<>+.", CONFIG), "<>+.");
+        // we don’t know where the memory pointer is after a loop
+        assert_eq!(minified("+[-]>><", CONFIG), "+[-]>><");
+        let wrap = Config {
+            boundary: Boundary::Wrap,
+            ..CONFIG
+        };
+        assert_eq!(minified("<>+.", wrap), "+.");
+        assert_eq!(minified("+[-]>><", wrap), "+[-]>");
+    }
+
+    #[test]
+    fn minify_keeps_saturating_runs() {
+        let saturate = Config {
+            overflow: Overflow::Saturate,
+            ..CONFIG
+        };
+        assert_eq!(minified("-+.", saturate), "-+.");
+        assert_eq!(minified("-+.", CONFIG), ".");
+    }
+}
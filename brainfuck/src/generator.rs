@@ -0,0 +1,70 @@
+/// Return a short brainfuck program printing exactly `text`.
+/// The program never relies on the cells wrapping around so it prints the
+/// same thing with any size of cell.
+pub fn text(text: &[u8]) -> String {
+    // without any loop, then with a loop of each size filling the cells
+    let mut best = generate(text, 0);
+    for counter in 2..=20 {
+        let program = generate(text, counter);
+        if program.len() < best.len() {
+            best = program;
+        }
+    }
+    best
+}
+
+/// Generate the program for `text`, starting with a multiplication loop
+/// running `counter` times if `counter` is not 0.
+/// The loop fills one cell for each multiple of `counter` close to a byte of
+/// the text, then each byte is printed from the cell which is the cheapest to
+/// reach and adjust.
+fn generate(text: &[u8], counter: u8) -> String {
+    let mut code = String::new();
+    // the value of the cells on the right of the counter
+    let mut cells: Vec<u32> = Vec::new();
+
+    if counter != 0 {
+        for &byte in text {
+            let factor = (byte as u32 + counter as u32 / 2) / counter as u32;
+            if factor != 0 && !cells.contains(&(factor * counter as u32)) {
+                cells.push(factor * counter as u32);
+            }
+        }
+        if !cells.is_empty() {
+            code.push_str(&"+".repeat(counter as usize));
+            code.push('[');
+            for &cell in &cells {
+                code.push('>');
+                code.push_str(&"+".repeat((cell / counter as u32) as usize));
+            }
+            code.push_str(&"<".repeat(cells.len()));
+            code.push_str("-]");
+        }
+    }
+    // the counter is 0 now, it can be used like the other cells
+    cells.insert(0, 0);
+
+    let mut pointer = 0;
+    for &byte in text {
+        let byte = byte as u32;
+        let cost = |&(i, &value): &(usize, &u32)| {
+            (i as i64 - pointer as i64).abs() + (value as i64 - byte as i64).abs()
+        };
+        let (target, _) = cells.iter().enumerate().min_by_key(cost).unwrap();
+
+        if target > pointer {
+            code.push_str(&">".repeat(target - pointer));
+        } else {
+            code.push_str(&"<".repeat(pointer - target));
+        }
+        pointer = target;
+        if byte > cells[target] {
+            code.push_str(&"+".repeat((byte - cells[target]) as usize));
+        } else {
+            code.push_str(&"-".repeat((cells[target] - byte) as usize));
+        }
+        cells[target] = byte;
+        code.push('.');
+    }
+    code
+}
@@ -0,0 +1,390 @@
+use crate::cell::Overflow;
+use crate::compiler::Instruction;
+use crate::options::CellWidth;
+use crate::tape::Boundary;
+use crate::vm::{Config, Eof, Error};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+
+/// A program compiled to x86-64 machine code.
+/// Only the 8-bit wrapping cells on a classic or infinite tape are supported.
+pub struct Jit {
+    code: *mut u8,
+    len: usize,
+    eof: Eof,
+    tape_size: usize,
+    /// index of the cell 0 in the tape
+    origin: usize,
+}
+
+/// Return code of the generated function
+const OK: u64 = 0;
+const OUT_OF_TAPE: u64 = 1;
+const IO_ERROR: u64 = 2;
+
+/// The state shared between the generated code and the I/O callbacks.
+/// The generated code relies on the offset of the first three fields.
+#[repr(C)]
+struct Context<R: Read, W: Write> {
+    /// the cell which made the program fail
+    pointer: *mut u8,
+    output: extern "sysv64" fn(*mut Context<R, W>, u8) -> u64,
+    input: extern "sysv64" fn(*mut Context<R, W>, *mut u8) -> u64,
+    eof: Eof,
+    input_stream: BufReader<R>,
+    output_stream: BufWriter<W>,
+    error: Option<std::io::Error>,
+}
+
+extern "sysv64" fn output_callback<R: Read, W: Write>(ctx: *mut Context<R, W>, byte: u8) -> u64 {
+    let ctx = unsafe { &mut *ctx };
+    match ctx.output_stream.write_all(&[byte]) {
+        Ok(()) => OK,
+        Err(e) => {
+            ctx.error = Some(e);
+            IO_ERROR
+        }
+    }
+}
+
+extern "sysv64" fn input_callback<R: Read, W: Write>(
+    ctx: *mut Context<R, W>,
+    cell: *mut u8,
+) -> u64 {
+    let ctx = unsafe { &mut *ctx };
+    let mut byte = [0];
+    let result = ctx
+        .output_stream
+        .flush()
+        .and_then(|()| ctx.input_stream.read_exact(&mut byte));
+    let value = match result {
+        Ok(()) => byte[0],
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => match ctx.eof {
+            Eof::Zero => 0,
+            Eof::MinusOne => 255,
+            Eof::Unchanged => return OK,
+        },
+        Err(e) => {
+            ctx.error = Some(e);
+            return IO_ERROR;
+        }
+    };
+    unsafe { *cell = value };
+    OK
+}
+
+impl Jit {
+    /// Compile the instructions to machine code.
+    /// Return `None` if the host, the configuration or an extension used by
+    /// the program is not supported or if
+    /// the executable memory can’t be mapped, the `Vm` should be used instead.
+    pub fn new(instructions: &[Instruction], cell: CellWidth, config: &Config) -> Option<Self> {
+        if !cfg!(all(target_arch = "x86_64", target_os = "linux"))
+            || cell != CellWidth::U8
+            || config.overflow != Overflow::Wrap
+            || config.boundary == Boundary::Wrap
+            || instructions.iter().any(Instruction::is_extension)
+        {
+            return None;
+        }
+
+        let code = assemble(instructions);
+        let (ptr, len) = mmap::executable(&code)?;
+        Some(Jit {
+            code: ptr,
+            len,
+            eof: config.eof,
+            tape_size: config.tape_size,
+            origin: match config.boundary {
+                Boundary::Infinite => config.tape_size / 2,
+                _ => 0,
+            },
+        })
+    }
+
+    /// Execute the program
+    pub fn run<R: Read, W: Write>(&self, input: R, output: W) -> Result<(), Error> {
+        let mut tape = vec![0u8; self.tape_size];
+        let mut ctx = Context {
+            pointer: std::ptr::null_mut(),
+            output: output_callback::<R, W>,
+            input: input_callback::<R, W>,
+            eof: self.eof,
+            input_stream: BufReader::new(input),
+            output_stream: BufWriter::new(output),
+            error: None,
+        };
+
+        let start = tape.as_mut_ptr();
+        let code: extern "sysv64" fn(*mut u8, *mut Context<R, W>, *mut u8, *mut u8) -> u64 =
+            unsafe { std::mem::transmute(self.code) };
+        let result = unsafe {
+            code(
+                start.add(self.origin),
+                &mut ctx,
+                start,
+                start.add(self.tape_size),
+            )
+        };
+
+        match result {
+            OUT_OF_TAPE => {
+                let cell = ctx.pointer as i64 - start as i64 - self.origin as i64;
+                Err(Error::OutOfTape(cell))
+            }
+            IO_ERROR => Err(Error::Io(ctx.error.unwrap())),
+            _ => ctx.output_stream.flush().map_err(Error::Io),
+        }
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        mmap::unmap(self.code, self.len);
+    }
+}
+
+/// Generate the machine code of a function with this signature:
+/// `extern "sysv64" fn(cell: *mut u8, ctx: *mut Context, start: *mut u8, end: *mut u8) -> u64`
+/// During the execution `rbx` holds the memory pointer, `r12` the context
+/// and `r13`, `r14` the bounds of the tape.
+fn assemble(instructions: &[Instruction]) -> Vec<u8> {
+    let mut asm = Assembler::default();
+
+    // push rbx; push r12; push r13; push r14; push r15
+    // the five pushes also align the stack on 16 bytes for the calls
+    asm.emit(&[0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57]);
+    // mov rbx, rdi; mov r12, rsi; mov r13, rdx; mov r14, rcx
+    asm.emit(&[
+        0x48, 0x89, 0xFB, 0x49, 0x89, 0xF4, 0x49, 0x89, 0xD5, 0x49, 0x89, 0xCE,
+    ]);
+
+    // start of each instruction in the code, the last one is the end of the program
+    let mut offsets = Vec::with_capacity(instructions.len() + 1);
+    for instruction in instructions {
+        offsets.push(asm.code.len());
+        match *instruction {
+            // add byte [rbx], value
+            Instruction::Add(value) => asm.emit(&[0x80, 0x03, value as u8]),
+            Instruction::Move(offset) => asm.move_pointer(offset),
+            Instruction::Output => {
+                // mov rdi, r12; movzx esi, byte [rbx]; call [r12 + 8]
+                asm.emit(&[0x4C, 0x89, 0xE7, 0x0F, 0xB6, 0x33]);
+                asm.emit(&[0x41, 0xFF, 0x54, 0x24, 0x08]);
+                asm.check_io();
+            }
+            Instruction::Input => {
+                // mov rdi, r12; mov rsi, rbx; call [r12 + 16]
+                asm.emit(&[0x4C, 0x89, 0xE7, 0x48, 0x89, 0xDE]);
+                asm.emit(&[0x41, 0xFF, 0x54, 0x24, 0x10]);
+                asm.check_io();
+            }
+            Instruction::JumpIfZero(target) => {
+                // cmp byte [rbx], 0; je target
+                asm.emit(&[0x80, 0x3B, 0x00, 0x0F, 0x84]);
+                asm.jump(Label::Instruction(target));
+            }
+            Instruction::JumpIfNotZero(target) => {
+                // cmp byte [rbx], 0; jne target
+                asm.emit(&[0x80, 0x3B, 0x00, 0x0F, 0x85]);
+                asm.jump(Label::Instruction(target));
+            }
+            // mov byte [rbx], 0
+            Instruction::Clear => asm.emit(&[0xC6, 0x03, 0x00]),
+            Instruction::MulAdd { offset, factor } => {
+                // cmp byte [rbx], 0; je end
+                asm.emit(&[0x80, 0x3B, 0x00, 0x74, 0x00]);
+                let end = asm.code.len();
+                // movzx eax, byte [rbx]; imul eax, eax, factor
+                asm.emit(&[0x0F, 0xB6, 0x03, 0x69, 0xC0]);
+                asm.emit(&factor.to_le_bytes());
+                // lea rcx, [rbx + offset]
+                asm.emit(&[0x48, 0x8D, 0x8B]);
+                asm.emit(&offset.to_le_bytes());
+                asm.check_bounds(Register::Rcx);
+                // add byte [rcx], al
+                asm.emit(&[0x00, 0x01]);
+                // the multiplication always fits in a short jump
+                asm.code[end - 1] = (asm.code.len() - end) as u8;
+            }
+            Instruction::Breakpoint => (),
+            Instruction::Scan(step) => {
+                let start = asm.code.len();
+                // cmp byte [rbx], 0; je end
+                asm.emit(&[0x80, 0x3B, 0x00, 0x74, 0x00]);
+                let end = asm.code.len();
+                asm.move_pointer(step);
+                // jmp start
+                asm.emit(&[0xE9]);
+                let rel = start as i32 - (asm.code.len() as i32 + 4);
+                asm.emit(&rel.to_le_bytes());
+                // the body of the loop always fits in a short jump
+                asm.code[end - 1] = (asm.code.len() - end) as u8;
+            }
+            _ => unreachable!("the extensions are rejected by `Jit::new`"),
+        }
+    }
+    offsets.push(asm.code.len());
+
+    // xor eax, eax
+    asm.emit(&[0x31, 0xC0]);
+    let epilogue = asm.code.len();
+    // pop r15; pop r14; pop r13; pop r12; pop rbx; ret
+    asm.emit(&[0x41, 0x5F, 0x41, 0x5E, 0x41, 0x5D, 0x41, 0x5C, 0x5B, 0xC3]);
+
+    let out_of_tape_rbx = asm.code.len();
+    // mov rcx, rbx
+    asm.emit(&[0x48, 0x89, 0xD9]);
+    let out_of_tape_rcx = asm.code.len();
+    // mov [r12], rcx; mov eax, OUT_OF_TAPE; jmp epilogue
+    asm.emit(&[0x49, 0x89, 0x0C, 0x24, 0xB8]);
+    asm.emit(&(OUT_OF_TAPE as u32).to_le_bytes());
+    asm.emit(&[0xE9]);
+    let rel = epilogue as i32 - (asm.code.len() as i32 + 4);
+    asm.emit(&rel.to_le_bytes());
+
+    let io_error = asm.code.len();
+    // mov eax, IO_ERROR; jmp epilogue
+    asm.emit(&[0xB8]);
+    asm.emit(&(IO_ERROR as u32).to_le_bytes());
+    asm.emit(&[0xE9]);
+    let rel = epilogue as i32 - (asm.code.len() as i32 + 4);
+    asm.emit(&rel.to_le_bytes());
+
+    for (position, label) in std::mem::take(&mut asm.fixups) {
+        let target = match label {
+            Label::Instruction(i) => offsets[i],
+            Label::OutOfTape(Register::Rbx) => out_of_tape_rbx,
+            Label::OutOfTape(Register::Rcx) => out_of_tape_rcx,
+            Label::IoError => io_error,
+        };
+        let rel = target as i32 - (position as i32 + 4);
+        asm.code[position..position + 4].copy_from_slice(&rel.to_le_bytes());
+    }
+
+    asm.code
+}
+
+#[derive(Clone, Copy)]
+enum Register {
+    Rbx,
+    Rcx,
+}
+
+/// Where a jump goes, known only once all the code was generated
+#[derive(Clone, Copy)]
+enum Label {
+    Instruction(usize),
+    /// The pointer in the register is outside of the tape
+    OutOfTape(Register),
+    IoError,
+}
+
+#[derive(Default)]
+struct Assembler {
+    code: Vec<u8>,
+    /// the position of the 32-bit relative jumps to patch
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Assembler {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    /// Emit the 32-bit relative address of the label
+    fn jump(&mut self, label: Label) {
+        self.fixups.push((self.code.len(), label));
+        self.emit(&[0; 4]);
+    }
+
+    /// Move the memory pointer, then stop if it is outside of the tape
+    fn move_pointer(&mut self, offset: i32) {
+        // add rbx, offset
+        self.emit(&[0x48, 0x81, 0xC3]);
+        self.emit(&offset.to_le_bytes());
+        self.check_bounds(Register::Rbx);
+    }
+
+    /// Stop if the pointer in the register is outside of the tape
+    fn check_bounds(&mut self, register: Register) {
+        let (below, above) = match register {
+            Register::Rbx => (0xEB, 0xF3),
+            Register::Rcx => (0xE9, 0xF1),
+        };
+        // cmp reg, r13; jb out_of_tape
+        self.emit(&[0x4C, 0x39, below, 0x0F, 0x82]);
+        self.jump(Label::OutOfTape(register));
+        // cmp reg, r14; jae out_of_tape
+        self.emit(&[0x4C, 0x39, above, 0x0F, 0x83]);
+        self.jump(Label::OutOfTape(register));
+    }
+
+    /// Stop if the I/O callback returned an error
+    fn check_io(&mut self) {
+        // test rax, rax; jnz io_error
+        self.emit(&[0x48, 0x85, 0xC0, 0x0F, 0x85]);
+        self.jump(Label::IoError);
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod mmap {
+    use std::ffi::c_void;
+
+    const PROT_READ: i32 = 1;
+    const PROT_WRITE: i32 = 2;
+    const PROT_EXEC: i32 = 4;
+    const MAP_PRIVATE: i32 = 2;
+    const MAP_ANONYMOUS: i32 = 0x20;
+
+    extern "C" {
+        fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: i32,
+            flags: i32,
+            fd: i32,
+            off: i64,
+        ) -> *mut c_void;
+        fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+        fn munmap(addr: *mut c_void, len: usize) -> i32;
+    }
+
+    /// Copy the code in a new executable mapping
+    pub fn executable(code: &[u8]) -> Option<(*mut u8, usize)> {
+        let len = code.len();
+        unsafe {
+            let ptr = mmap(
+                std::ptr::null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            // MAP_FAILED
+            if ptr as isize == -1 {
+                return None;
+            }
+            std::ptr::copy_nonoverlapping(code.as_ptr(), ptr as *mut u8, len);
+            if mprotect(ptr, len, PROT_READ | PROT_EXEC) != 0 {
+                munmap(ptr, len);
+                return None;
+            }
+            Some((ptr as *mut u8, len))
+        }
+    }
+
+    pub fn unmap(ptr: *mut u8, len: usize) {
+        unsafe { munmap(ptr as *mut c_void, len) };
+    }
+}
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+mod mmap {
+    pub fn executable(_code: &[u8]) -> Option<(*mut u8, usize)> {
+        None
+    }
+
+    pub fn unmap(_ptr: *mut u8, _len: usize) {}
+}
@@ -1,27 +1,322 @@
+mod cell;
+mod checker;
+mod compiler;
+mod debugger;
+mod dialect;
+mod formatter;
+mod generator;
+mod jit;
+mod options;
+mod parser;
+mod profiler;
 mod tape;
+mod transpiler;
 mod vm;
 
+use cell::Cell;
+use compiler::Folding;
+use dialect::Dialect;
+use options::{CellWidth, Command, Options};
+use parser::Program;
 use std::fs::File;
 use std::io::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The number of instructions executed between two looks at the clock
+const SLICE: u64 = 1_000_000;
 
 fn main() {
     let mut args = std::env::args();
     let exe = args.next().unwrap();
-    let file = match args.next() {
-        Some(f) => f,
-        None => {
-            println!("usage:\n\t{} [filename]", exe);
+    let options = match Options::parse(args) {
+        Ok(options) => options,
+        Err(e) => {
+            println!("{}\n{}", e, options::USAGE.replace("{exe}", &exe));
+            return;
+        }
+    };
+
+    let buffer = match (&options.text, &options.file) {
+        (Some(text), _) if options.command == Command::GenText => text.as_bytes().to_vec(),
+        (_, Some(file)) => {
+            let mut file = File::open(file).unwrap();
+            let mut buffer = Vec::new();
+            file.read_to_end(&mut buffer).unwrap();
+            buffer
+        }
+        // the options always give us a file or a text for gen-text
+        (_, None) => unreachable!(),
+    };
+    if options.command == Command::GenText {
+        let program = generator::text(&buffer);
+        println!("{}", program);
+        eprintln!("The program is {} instructions long", program.len());
+        return;
+    }
+
+    // the instructions are ASCII, only the comments can be anything else
+    let buffer = String::from_utf8_lossy(&buffer).chars().collect::<Vec<char>>();
+    let source = parser::positions(&buffer);
+    let file = options.file.as_deref().unwrap_or_default();
+    let dialect = match Dialect::select(options.dialect.as_deref(), file) {
+        Ok(dialect) => dialect,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let extensions = parser::Extensions {
+        cow: dialect.as_ref().is_some_and(|dialect| dialect.cow),
+        ..options.extensions
+    };
+    let source = match dialect {
+        Some(dialect) => dialect.translate(&source),
+        None => Ok(source),
+    };
+    let (source, program) = match source.and_then(|source| {
+        let program = parser::parse(&source, extensions)?;
+        Ok((source, program))
+    }) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("Invalid program: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let result = match options.command {
+        Command::Run | Command::Debug | Command::Profile => interpret(&program, &options),
+        Command::Jit => jit(&program, &options),
+        Command::GenText => unreachable!(),
+        Command::Fmt => {
+            let source: Vec<char> = source.iter().map(|&(c, _)| c).collect();
+            print!("{}", formatter::format(&source, extensions));
             return;
         }
+        Command::Check => {
+            let findings = checker::check(&program, &options.config);
+            for finding in &findings {
+                let pos = program.positions[finding.instruction];
+                println!("{}: {}", pos, finding.problem);
+            }
+            if !findings.is_empty() {
+                std::process::exit(1);
+            }
+            return;
+        }
+        Command::Minify => {
+            println!(
+                "{}",
+                formatter::minify(&program, &options.config, options.cell != CellWidth::Bignum)
+            );
+            return;
+        }
+        Command::C => {
+            let compiled = compiler::compile(&program, Folding::new(true, options.config.overflow));
+            match transpiler::to_c(&compiled.instructions, options.cell, &options.config) {
+                Ok(c) => print!("{}", c),
+                Err(e) => {
+                    eprintln!("Can’t translate the program: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("Runtime error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Execute the program with the `Vm`
+fn interpret(program: &Program, options: &Options) -> Result<(), vm::Error> {
+    match options.cell {
+        CellWidth::U8 => run::<u8>(program, options),
+        CellWidth::U16 => run::<u16>(program, options),
+        CellWidth::U32 => run::<u32>(program, options),
+        CellWidth::U64 => run::<u64>(program, options),
+        CellWidth::Bignum => run::<num_bigint::BigInt>(program, options),
+    }
+}
+
+/// Execute the program with the JIT, or with the `Vm` if the JIT can’t be used
+fn jit(program: &Program, options: &Options) -> Result<(), vm::Error> {
+    let compiled = compiler::compile(program, Folding::new(true, options.config.overflow));
+    // the machine code can’t be stopped
+    let limited = options.max_steps.is_some() || options.timeout.is_some();
+    match jit::Jit::new(&compiled.instructions, options.cell, &options.config) {
+        Some(jit) if !limited => jit.run(input(options)?, std::io::stdout()),
+        _ => {
+            eprintln!("The JIT can’t be used, falling back to the interpreter");
+            interpret(program, options)
+        }
+    }
+}
+
+/// Execute the program with cells of type `C`, in the debugger or the profiler if asked
+fn run<C: Cell>(program: &Program, options: &Options) -> Result<(), vm::Error> {
+    let folding = match options.command {
+        // the threads must take the same turns with any cells and command
+        _ if options.extensions.brainfork => Folding::unfolded(),
+        // the profiler counts the iterations of every loop
+        Command::Profile => Folding::none(),
+        _ => Folding::new(C::BOUNDED, options.config.overflow),
+    };
+    let compiled = compiler::compile(program, folding);
+    let mut input = input(options)?;
+    // the debugger and the profiler don’t stop on `--timeout`
+    let executed = !matches!(options.command, Command::Debug | Command::Profile);
+    if let (true, Some(timeout)) = (executed, options.timeout) {
+        input = watchdog(input, timeout);
+    }
+    let mut vm = vm::Vm::<C, _, _>::new(
+        &compiled.instructions,
+        options.config,
+        input,
+        std::io::stdout(),
+    );
+    if let Some(file) = &options.resume {
+        vm.restore(&std::fs::read_to_string(file).map_err(vm::Error::Io)?)?;
+    }
+    if options.command == Command::Debug {
+        let mut debugger = debugger::Debugger::new(vm, program, &compiled);
+        debugger.run(std::io::stdin().lock());
+        return Ok(());
+    }
+    if options.command == Command::Profile {
+        return profile(vm, program, &compiled, options);
+    }
+    execute(&mut vm, options)
+}
+
+/// Execute the program until it’s finished, saving its state regularly if
+/// asked. The program is stopped with an error once it executed
+/// `--max-steps` instructions or ran for `--timeout`.
+fn execute<C: Cell, R: Read, W: Write>(
+    vm: &mut vm::Vm<C, R, W>,
+    options: &Options,
+) -> Result<(), vm::Error> {
+    let start = Instant::now();
+    let mut next_save = options.save_every;
+    loop {
+        // we stop regularly to look at the clock and to save the state
+        let mut budget = SLICE;
+        if let Some(max) = options.max_steps {
+            budget = budget.min(max - vm.executed());
+        }
+        if options.save.is_some() {
+            budget = budget.min(next_save - vm.executed());
+        }
+        match vm.run_for(budget)? {
+            vm::Status::Finished => return Ok(()),
+            vm::Status::OutOfBudget if options.max_steps == Some(vm.executed()) => {
+                vm.flush()?;
+                return Err(vm::Error::OutOfBudget(vm.executed()));
+            }
+            vm::Status::OutOfBudget => (),
+            // the input is not blocking, let’s wait for it without spinning
+            vm::Status::WaitingForInput => std::thread::sleep(Duration::from_millis(10)),
+        }
+        if let Some(timeout) = options.timeout {
+            if start.elapsed() > timeout {
+                vm.flush()?;
+                return Err(vm::Error::Timeout(timeout));
+            }
+        }
+        if let Some(file) = &options.save {
+            if vm.executed() == next_save {
+                save(vm, file)?;
+                next_save += options.save_every;
+            }
+        }
+    }
+}
+
+/// An input which tells the watchdog when the program is waiting for it
+struct Watched {
+    input: Box<dyn Read>,
+    reading: Arc<AtomicBool>,
+}
+
+impl Read for Watched {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reading.store(true, Ordering::SeqCst);
+        let read = self.input.read(buf);
+        self.reading.store(false, Ordering::SeqCst);
+        read
+    }
+}
+
+/// Exit the process once the program ran for `timeout` while it’s waiting
+/// for its `input`, `execute` can’t stop it while it’s blocked. The `Vm`
+/// flushes its output before reading its input so nothing is lost.
+fn watchdog(input: Box<dyn Read>, timeout: Duration) -> Box<dyn Read> {
+    let reading = Arc::new(AtomicBool::new(false));
+    let watched = Watched {
+        input,
+        reading: reading.clone(),
     };
+    std::thread::spawn(move || {
+        std::thread::sleep(timeout);
+        // otherwise `execute` stops the program after its current slice
+        while !reading.load(Ordering::SeqCst) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        eprintln!("Runtime error: {}", vm::Error::Timeout(timeout));
+        std::process::exit(1);
+    });
+    Box::new(watched)
+}
 
-    let mut file = File::open(file).unwrap();
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).unwrap();
+/// Save the state of the `Vm` in `file`. The previous state is replaced only
+/// once the new one is on the disk, so we can always resume from one of them.
+fn save<C: Cell, R: Read, W: Write>(vm: &mut vm::Vm<C, R, W>, file: &str) -> Result<(), vm::Error> {
+    let tmp = format!("{}.tmp", file);
+    let mut out = std::io::BufWriter::new(File::create(&tmp).map_err(vm::Error::Io)?);
+    vm.save(&mut out)?;
+    let out = out
+        .into_inner()
+        .map_err(|e| vm::Error::Io(e.into_error()))?;
+    out.sync_all().map_err(vm::Error::Io)?;
+    std::fs::rename(tmp, file).map_err(vm::Error::Io)
+}
 
-    let buffer = buffer.iter().map(|&a| a as char).collect::<Vec<char>>();
-    let mut vm = vm::Vm::new(&buffer);
+/// Execute the program and report how many times each instruction was executed
+fn profile<C: Cell, R: Read, W: Write>(
+    mut vm: vm::Vm<C, R, W>,
+    program: &Program,
+    compiled: &compiler::Compiled,
+    options: &Options,
+) -> Result<(), vm::Error> {
+    let mut profiler = profiler::Profiler::new(program, compiled);
+    let mut result = Ok(());
     while !vm.finished() {
-        vm.cycle();
+        profiler.record(vm.instruction_pointer());
+        if let Err(e) = vm.cycle() {
+            result = Err(e);
+            break;
+        }
+    }
+    vm.flush()?;
+
+    eprint!("{}", profiler.report(10));
+    if let Some(file) = &options.folded {
+        std::fs::write(file, profiler.folded()).map_err(vm::Error::Io)?;
     }
+    if let Some(file) = &options.counts {
+        std::fs::write(file, profiler.counts()).map_err(vm::Error::Io)?;
+    }
+    result
+}
+
+/// The input of the program, the debugger needs stdin for its commands
+fn input(options: &Options) -> Result<Box<dyn Read>, vm::Error> {
+    Ok(match &options.input {
+        Some(file) => Box::new(File::open(file).map_err(vm::Error::Io)?),
+        None if options.command == Command::Debug => Box::new(std::io::empty()),
+        None => Box::new(std::io::stdin()),
+    })
 }
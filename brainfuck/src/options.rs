@@ -0,0 +1,275 @@
+use crate::cell::Overflow;
+use crate::parser::Extensions;
+use crate::tape::{self, Boundary};
+use crate::vm::{Config, Eof};
+use std::time::Duration;
+
+pub const USAGE: &str = "\
+usage:
+\t{exe} [command] [options] [filename]
+
+commands:
+\trun                              execute the program, this is the default
+\tjit                              execute the program compiled to x86-64 machine code
+\tc                                print the program translated to C
+\tdebug                            execute the program in an interactive debugger
+\tprofile                          execute the program and report its hottest loops on stderr
+\tgen-text                         print a program printing the content of the file
+\tfmt                              print the program indented by loop depth
+\tminify                           print the program without comments nor useless instructions
+\tcheck                            report the problems found in the program without running it
+
+options:
+\t--cell <8|16|32|64|bignum>       size of the cells, 8 by default
+\t--overflow <wrap|saturate|error> what happens when a cell overflow, wrap by default
+\t--eof <0|-1|unchanged>           value read on EOF, 0 by default
+\t--tape <classic|wrap|infinite>   what happens at the ends of the tape, infinite by default
+\t--tape-size <cells>              number of cells of the tape, 30000 by default,
+\t                                 or its maximum for an infinite tape, 16777216 by default
+\t--dialect <name|file>            language of the program: brainfuck, ook, blub, cow or
+\t                                 a token map file, chosen from the file extension by default
+\t--pbrain                         enable the pbrain procedures: `(` `)` and `:`
+\t--brainfork                      enable the Brainfork threads: `Y`
+\t--extended                       enable the Extended Brainfuck Type I instructions
+\t--text <text>                    the text printed by the program of gen-text, instead of
+\t                                 the content of a file
+\t--save <file>                    save the state of the program in this file regularly
+\t--save-every <instructions>      number of instructions between two saves, 100000000 by default
+\t--resume <file>                  resume the program from the state saved in this file
+\t--max-steps <instructions>       stop the program if it executes more instructions
+\t--timeout <seconds>              stop the program if it runs for longer
+\t--input <file>                   read the input of the program from a file instead of
+\t                                 stdin, the input is empty by default in the debugger
+\t--folded <file>                  write the folded stacks of the loops for a flamegraph
+\t                                 in this file when profiling
+\t--counts <file>                  write the execution count of each instruction in this
+\t                                 file when profiling";
+
+/// What to do with the program
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Run,
+    /// Execute the program with the x86-64 JIT
+    Jit,
+    /// Print the program translated to C
+    C,
+    /// Execute the program in the interactive debugger
+    Debug,
+    /// Execute the program and report where the time was spent
+    Profile,
+    /// Generate a program printing a text
+    GenText,
+    /// Print the program formatted
+    Fmt,
+    /// Print the program minified
+    Minify,
+    /// Report the problems of the program
+    Check,
+}
+
+/// The type of the cells of the tape
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CellWidth {
+    U8,
+    U16,
+    U32,
+    U64,
+    /// Arbitrary-precision cells
+    Bignum,
+}
+
+pub struct Options {
+    pub command: Command,
+    /// Always set, except for gen-text with a `text`
+    pub file: Option<String>,
+    /// The text to print with gen-text
+    pub text: Option<String>,
+    pub cell: CellWidth,
+    pub config: Config,
+    /// The instructions added to brainfuck
+    pub extensions: Extensions,
+    /// The dialect asked by the user, a name or a token map file
+    pub dialect: Option<String>,
+    /// Where the state of the program is saved
+    pub save: Option<String>,
+    /// Number of instructions between two saves
+    pub save_every: u64,
+    /// The state to resume from
+    pub resume: Option<String>,
+    /// The maximum number of instructions executed by the program
+    pub max_steps: Option<u64>,
+    /// The maximum time the program can run
+    pub timeout: Option<Duration>,
+    /// The file to use as input of the program
+    pub input: Option<String>,
+    /// Where the profiler writes the folded stacks
+    pub folded: Option<String>,
+    /// Where the profiler writes the execution counts
+    pub counts: Option<String>,
+}
+
+impl Options {
+    /// Parse the command line arguments, without the name of the executable
+    pub fn parse(args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut args = args.peekable();
+        let command = match args.peek().map(String::as_str) {
+            Some("run") => Some(Command::Run),
+            Some("jit") => Some(Command::Jit),
+            Some("c") => Some(Command::C),
+            Some("debug") => Some(Command::Debug),
+            Some("profile") => Some(Command::Profile),
+            Some("gen-text") => Some(Command::GenText),
+            Some("fmt") => Some(Command::Fmt),
+            Some("minify") => Some(Command::Minify),
+            Some("check") => Some(Command::Check),
+            _ => None,
+        };
+        // the command is optional
+        if command.is_some() {
+            args.next();
+        }
+        let command = command.unwrap_or(Command::Run);
+
+        let mut file = None;
+        let mut cell = CellWidth::U8;
+        let mut overflow = Overflow::Wrap;
+        let mut eof = Eof::Zero;
+        let mut boundary = Boundary::Infinite;
+        let mut tape_size = None;
+        let mut text = None;
+        let mut dialect = None;
+        let mut pbrain = false;
+        let mut brainfork = false;
+        let mut extended = false;
+        let mut save = None;
+        let mut save_every = 100_000_000;
+        let mut resume = None;
+        let mut max_steps = None;
+        let mut timeout = None;
+        let mut input = None;
+        let mut folded = None;
+        let mut counts = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--cell" => {
+                    cell = match value(&arg, args.next())?.as_str() {
+                        "8" => CellWidth::U8,
+                        "16" => CellWidth::U16,
+                        "32" => CellWidth::U32,
+                        "64" => CellWidth::U64,
+                        "bignum" => CellWidth::Bignum,
+                        v => return Err(format!("invalid cell size `{}`", v)),
+                    }
+                }
+                "--overflow" => {
+                    overflow = match value(&arg, args.next())?.as_str() {
+                        "wrap" => Overflow::Wrap,
+                        "saturate" => Overflow::Saturate,
+                        "error" => Overflow::Error,
+                        v => return Err(format!("invalid overflow mode `{}`", v)),
+                    }
+                }
+                "--eof" => {
+                    eof = match value(&arg, args.next())?.as_str() {
+                        "0" => Eof::Zero,
+                        "-1" => Eof::MinusOne,
+                        "unchanged" => Eof::Unchanged,
+                        v => return Err(format!("invalid EOF behaviour `{}`", v)),
+                    }
+                }
+                "--tape" => {
+                    boundary = match value(&arg, args.next())?.as_str() {
+                        "classic" => Boundary::Classic,
+                        "wrap" => Boundary::Wrap,
+                        "infinite" => Boundary::Infinite,
+                        v => return Err(format!("invalid tape `{}`", v)),
+                    }
+                }
+                "--tape-size" => {
+                    tape_size = match value(&arg, args.next())?.parse() {
+                        Ok(0) | Err(_) => return Err(String::from("invalid tape size")),
+                        Ok(size) => Some(size),
+                    }
+                }
+                "--dialect" => dialect = Some(value(&arg, args.next())?),
+                "--pbrain" => pbrain = true,
+                "--brainfork" => brainfork = true,
+                "--extended" => extended = true,
+                "--text" => text = Some(value(&arg, args.next())?),
+                "--save" => save = Some(value(&arg, args.next())?),
+                "--save-every" => {
+                    save_every = match value(&arg, args.next())?.parse() {
+                        Ok(0) | Err(_) => {
+                            return Err(String::from("invalid number of instructions"))
+                        }
+                        Ok(n) => n,
+                    }
+                }
+                "--resume" => resume = Some(value(&arg, args.next())?),
+                "--max-steps" => {
+                    max_steps = match value(&arg, args.next())?.parse() {
+                        Ok(n) => Some(n),
+                        Err(_) => return Err(String::from("invalid number of instructions")),
+                    }
+                }
+                "--timeout" => {
+                    timeout = match value(&arg, args.next())?.parse() {
+                        Ok(seconds) if (0.0..1e9).contains(&seconds) => {
+                            Some(Duration::from_secs_f64(seconds))
+                        }
+                        _ => return Err(String::from("invalid timeout")),
+                    }
+                }
+                "--input" => input = Some(value(&arg, args.next())?),
+                "--folded" => folded = Some(value(&arg, args.next())?),
+                "--counts" => counts = Some(value(&arg, args.next())?),
+                _ if arg.starts_with("--") => return Err(format!("unknown option `{}`", arg)),
+                _ if file.is_none() => file = Some(arg),
+                _ => return Err(format!("unexpected argument `{}`", arg)),
+            }
+        }
+
+        Ok(Options {
+            command,
+            file: match file {
+                None if command != Command::GenText || text.is_none() => {
+                    return Err(String::from("missing filename"))
+                }
+                file => file,
+            },
+            text,
+            cell,
+            config: Config {
+                overflow,
+                eof,
+                boundary,
+                tape_size: tape_size.unwrap_or(match boundary {
+                    Boundary::Classic | Boundary::Wrap => tape::CLASSIC_SIZE,
+                    Boundary::Infinite => tape::INFINITE_SIZE,
+                }),
+            },
+            extensions: Extensions {
+                debug: command == Command::Debug,
+                pbrain,
+                brainfork,
+                extended,
+                cow: false,
+            },
+            dialect,
+            save,
+            save_every,
+            resume,
+            max_steps,
+            timeout,
+            input,
+            folded,
+            counts,
+        })
+    }
+}
+
+/// Return the value of an option or an error if it’s missing
+fn value(option: &str, value: Option<String>) -> Result<String, String> {
+    value.ok_or_else(|| format!("missing value for `{}`", option))
+}
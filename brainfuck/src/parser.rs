@@ -0,0 +1,189 @@
+use std::fmt;
+
+/// A position in the source code, lines and columns start at 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    /// A `[` or a `(` without its matching `]` or `)`
+    UnmatchedOpen(char, Position),
+    /// A `]` or a `)` without its matching `[` or `(`
+    UnmatchedClose(char, Position),
+    /// A token of a dialect which has no equivalent in brainfuck
+    Untranslatable(String, Position),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::UnmatchedOpen(c, pos) => write!(f, "unmatched `{}` at {}", c, pos),
+            ParseError::UnmatchedClose(c, pos) => write!(f, "unmatched `{}` at {}", c, pos),
+            ParseError::Untranslatable(token, pos) => write!(
+                f,
+                "`{}` at {} has no brainfuck equivalent, \
+                 `mOO`, `MMM`, `OOM` and `oom` are not supported",
+                token, pos
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// The optional instructions recognized by the parser
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Extensions {
+    /// `#` is a breakpoint for the debugger
+    pub debug: bool,
+    /// pbrain: `(` and `)` define a procedure and `:` calls it
+    pub pbrain: bool,
+    /// Brainfork: `Y` forks the current thread
+    pub brainfork: bool,
+    /// Extended Brainfuck Type I: `@` `$` `!` `{` `}` `~` `^` `&` `|`
+    pub extended: bool,
+    /// COW: `m` is the `Moo` of COW and `0` its `OOO`, which have no
+    /// brainfuck equivalent
+    pub cow: bool,
+}
+
+impl Extensions {
+    /// Return `true` if `c` is an instruction
+    pub fn contains(&self, c: char) -> bool {
+        "+-<>.,[]".contains(c)
+            || (self.debug && c == '#')
+            || (self.pbrain && "():".contains(c))
+            || (self.brainfork && c == 'Y')
+            || (self.extended && "@$!{}~^&|".contains(c))
+            || (self.cow && "m0".contains(c))
+    }
+}
+
+/// A brainfuck program stripped of its comments
+pub struct Program {
+    /// The brainfuck instructions
+    pub code: Vec<char>,
+    /// The position in the source code of each instruction
+    pub positions: Vec<Position>,
+    /// For each bracket or parenthesis, the index of its match in `code`
+    pub brackets: Vec<Option<usize>>,
+}
+
+/// Return each character of the source code with its position
+pub fn positions(source: &[char]) -> Vec<(char, Position)> {
+    let mut pos = Position { line: 1, column: 1 };
+    source
+        .iter()
+        .map(|&c| {
+            let current = pos;
+            if c == '\n' {
+                pos.line += 1;
+                pos.column = 1;
+            } else {
+                pos.column += 1;
+            }
+            (c, current)
+        })
+        .collect()
+}
+
+/// Parse the brainfuck source code and match all its brackets.
+/// Every character that is not a brainfuck instruction or one of the enabled
+/// `extensions` is considered as a comment.
+pub fn parse(source: &[(char, Position)], extensions: Extensions) -> Result<Program, ParseError> {
+    let mut program = Program {
+        code: Vec::new(),
+        positions: Vec::new(),
+        brackets: Vec::new(),
+    };
+    // index of the `[` and `(` waiting for their matching `]` and `)`
+    let mut opened: Vec<usize> = Vec::new();
+
+    for &(c, pos) in source {
+        if !extensions.contains(c) {
+            continue;
+        }
+        let index = program.code.len();
+        program.code.push(c);
+        program.positions.push(pos);
+        program.brackets.push(None);
+
+        if c == '[' || c == '(' {
+            opened.push(index);
+        } else if c == ']' || c == ')' {
+            let open = if c == ']' { '[' } else { '(' };
+            let start = match opened.pop() {
+                Some(start) if program.code[start] == open => start,
+                // a `(` closed by a `]` is reported on the `(`
+                Some(start) => {
+                    let code = program.code[start];
+                    return Err(ParseError::UnmatchedOpen(code, program.positions[start]));
+                }
+                None => return Err(ParseError::UnmatchedClose(c, pos)),
+            };
+            program.brackets[start] = Some(index);
+            program.brackets[index] = Some(start);
+        }
+    }
+
+    match opened.pop() {
+        Some(start) => Err(ParseError::UnmatchedOpen(
+            program.code[start],
+            program.positions[start],
+        )),
+        None => Ok(program),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(source: &str) -> Result<Program, ParseError> {
+        let source: Vec<char> = source.chars().collect();
+        let extensions = Extensions {
+            pbrain: true,
+            ..Extensions::default()
+        };
+        parse(&positions(&source), extensions)
+    }
+
+    #[test]
+    fn brackets() {
+        let program = parsed("+[>[-] comment ]").unwrap();
+        assert_eq!(program.code, ['+', '[', '>', '[', '-', ']', ']']);
+        assert_eq!(
+            program.brackets,
+            [None, Some(6), None, Some(5), None, Some(3), Some(1)]
+        );
+        let program = parsed("(+)[]").unwrap();
+        assert_eq!(program.brackets, [Some(2), None, Some(0), Some(4), Some(3)]);
+    }
+
+    #[test]
+    fn unmatched() {
+        let at = |line, column| Position { line, column };
+        assert!(matches!(
+            parsed("+[\n[-]"),
+            Err(ParseError::UnmatchedOpen('[', pos)) if pos == at(1, 2)
+        ));
+        assert!(matches!(
+            parsed("[-]\n-]"),
+            Err(ParseError::UnmatchedClose(']', pos)) if pos == at(2, 2)
+        ));
+        // a `(` closed by a `]` is reported on the `(`
+        assert!(matches!(
+            parsed("+(]"),
+            Err(ParseError::UnmatchedOpen('(', pos)) if pos == at(1, 2)
+        ));
+    }
+}
@@ -0,0 +1,136 @@
+use crate::compiler::{Compiled, Instruction};
+use crate::parser::Program;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Count how many times each instruction of a program is executed.
+/// The program should be compiled without folding any loop so the
+/// iterations of every loop can be counted.
+pub struct Profiler<'a> {
+    program: &'a Program,
+    compiled: &'a Compiled,
+    counts: Vec<u64>,
+}
+
+/// A loop of the program, `start` and `end` are the index of its `[` and `]`
+/// in the compiled instructions
+struct Loop {
+    start: usize,
+    end: usize,
+}
+
+impl<'a> Profiler<'a> {
+    pub fn new(program: &'a Program, compiled: &'a Compiled) -> Self {
+        Profiler {
+            program,
+            compiled,
+            counts: vec![0; compiled.instructions.len()],
+        }
+    }
+
+    /// Count one execution of this instruction
+    pub fn record(&mut self, instruction: usize) {
+        self.counts[instruction] += 1;
+    }
+
+    /// Number of source instructions executed by this instruction,
+    /// a folded `+++` counts for three instructions
+    fn cost(&self, instruction: usize) -> u64 {
+        self.counts[instruction] * self.compiled.sources[instruction].len() as u64
+    }
+
+    fn loops(&self) -> Vec<Loop> {
+        self.compiled
+            .instructions
+            .iter()
+            .enumerate()
+            .filter_map(|(start, instruction)| match *instruction {
+                Instruction::JumpIfZero(after) => Some(Loop {
+                    start,
+                    end: after - 1,
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Return the total number of instructions executed and the `top`
+    /// loops in which most of them were executed
+    pub fn report(&self, top: usize) -> String {
+        let total: u64 = (0..self.counts.len()).map(|i| self.cost(i)).sum();
+        let mut report = String::new();
+        // writing to a `String` never fails
+        writeln!(report, "executed {} instructions", total).unwrap();
+
+        let mut loops: Vec<(u64, Loop)> = self
+            .loops()
+            .into_iter()
+            .map(|l| ((l.start..=l.end).map(|i| self.cost(i)).sum(), l))
+            .collect();
+        loops.sort_by_key(|(cost, _)| std::cmp::Reverse(*cost));
+        if loops.is_empty() {
+            return report;
+        }
+
+        writeln!(report, "hottest loops:").unwrap();
+        writeln!(report, "{:>24} {:>12}  loop", "instructions", "iterations").unwrap();
+        for (cost, l) in loops.iter().take(top) {
+            let percent = *cost as f64 * 100.0 / total.max(1) as f64;
+            writeln!(
+                report,
+                "{:>15} ({:>5.1}%) {:>12}  {} to {}",
+                cost,
+                percent,
+                self.counts[l.end],
+                self.program.positions[self.compiled.sources[l.start].start],
+                self.program.positions[self.compiled.sources[l.end].start],
+            )
+            .unwrap();
+        }
+        report
+    }
+
+    /// Return how many times each source instruction was executed,
+    /// one instruction per line: `line:column instruction count`
+    pub fn counts(&self) -> String {
+        let mut counts = String::new();
+        for (i, source) in self.compiled.sources.iter().enumerate() {
+            for code in source.clone() {
+                let pos = self.program.positions[code];
+                writeln!(
+                    counts,
+                    "{}:{}\t{}\t{}",
+                    pos.line, pos.column, self.program.code[code], self.counts[i]
+                )
+                .unwrap();
+            }
+        }
+        counts
+    }
+
+    /// Return the number of instructions executed in each stack of nested
+    /// loops, in the folded format used by the flamegraph tools
+    pub fn folded(&self) -> String {
+        let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+        let mut stack = vec![String::from("program")];
+        for (i, instruction) in self.compiled.instructions.iter().enumerate() {
+            // the brackets are part of their loop
+            if let Instruction::JumpIfZero(_) = instruction {
+                let pos = self.program.positions[self.compiled.sources[i].start];
+                stack.push(format!("loop@{}:{}", pos.line, pos.column));
+            }
+            if self.counts[i] > 0 {
+                *stacks.entry(stack.join(";")).or_default() += self.cost(i);
+            }
+            if let Instruction::JumpIfNotZero(_) = instruction {
+                stack.pop();
+            }
+        }
+
+        let mut folded = String::new();
+        for (stack, cost) in stacks {
+            writeln!(folded, "{} {}", stack, cost).unwrap();
+        }
+        folded
+    }
+}
@@ -1,59 +1,134 @@
+use crate::cell::Cell;
 use std::ops::{Index, IndexMut};
 
-pub struct Tape {
-    vec: Vec<i8>,
+/// Number of cells of the tape in the classic and wrapping modes
+pub const CLASSIC_SIZE: usize = 30000;
+/// Maximum number of cells of the tape in the infinite mode
+pub const INFINITE_SIZE: usize = 1 << 24;
+
+/// What happens at the ends of the tape
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Boundary {
+    /// A fixed number of cells starting at 0, moving outside of them is an error
+    Classic,
+    /// A fixed number of cells, moving past one end of the tape goes to the other end
+    Wrap,
+    /// The tape extends in both directions as needed, up to a maximum number of cells
+    Infinite,
 }
 
-impl Tape {
-    pub fn new() -> Self {
-        Tape { vec: Vec::new() }
-    }
+pub struct Tape<C: Cell> {
+    vec: Vec<C>,
+    /// returned when reading a cell which was never allocated
+    zero: C,
+    boundary: Boundary,
+    /// number of cells of the tape, or its maximum in the infinite mode
+    size: usize,
 }
 
-/// You can index into the tape as if it was an array.
-/// There is two major differences:
-/// - You don’t need to increase the size of the array or choose a size at start.
-///   You can indexes whatever you want and the tape will grow to this size.
-/// - You can use negative indexes: Since I was too bored to implements some
-///   real negative indexes or something, when you use a positive indexe it will
-///   use the even number and the odd number for negatives indexes.
-///   Here is a scheme to understand how you number are placed in the tape:
-///   .---+----+---+----+---+----+---+----.
-///   | 0 | -1 | 1 | -2 | 2 | -3 | 3 | -4 | indexes you provide
-///   '---+----+---+----+---+----+---+----'
-///     0   1    2   3    4   5    6   7    indexes in the internal vector
-///
-impl Index<i32> for Tape {
-    type Output = i8;
-    fn index(&self, mut i: i32) -> &Self::Output {
-        if i >= 0 {
-            i = i * 2;
-        } else {
-            i = i * -2 - 1;
+impl<C: Cell> Tape<C> {
+    pub fn new(boundary: Boundary, size: usize) -> Self {
+        let vec = match boundary {
+            Boundary::Classic | Boundary::Wrap => vec![C::default(); size],
+            Boundary::Infinite => Vec::new(),
+        };
+        Tape {
+            vec,
+            zero: C::default(),
+            boundary,
+            size,
         }
-        let i = i as usize;
+    }
 
-        // if we don’t have the mutability we can’t allocate
-        // the missings cells
+    /// Return the index of the cell at `offset` from `pointer`.
+    /// Return `None` if this cell is outside of the tape.
+    pub fn offset(&self, pointer: i32, offset: i32) -> Option<i32> {
+        let target = pointer as i64 + offset as i64;
+        let size = self.size as i64;
+        match self.boundary {
+            Boundary::Wrap => Some(target.rem_euclid(size) as i32),
+            _ if inside(self.boundary, self.size, target) => Some(target as i32),
+            _ => None,
+        }
+    }
 
-        if i >= self.vec.len() {
-            return &0;
+    /// Return the index and the value of every cell which is not 0
+    pub fn cells(&self) -> impl Iterator<Item = (i32, &C)> + '_ {
+        self.vec
+            .iter()
+            .enumerate()
+            .filter(|(_, cell)| !cell.is_zero())
+            .map(move |(i, cell)| match self.boundary {
+                Boundary::Classic | Boundary::Wrap => (i as i32, cell),
+                Boundary::Infinite => (external(i) as i32, cell),
+            })
+    }
+
+    /// Return the index of `i` in the internal vector
+    fn position(&self, i: i32) -> usize {
+        match self.boundary {
+            Boundary::Classic | Boundary::Wrap => i as usize,
+            Boundary::Infinite => internal(i as i64),
         }
-        &self.vec[i]
     }
 }
 
-impl IndexMut<i32> for Tape {
-    fn index_mut<'a>(&'a mut self, mut i: i32) -> &'a mut Self::Output {
-        if i >= 0 {
-            i = i * 2;
-        } else {
-            i = i * -2 - 1;
-        }
-        let i = i as usize;
+/// Return `true` if the cell at this index is on a tape with this `boundary`
+/// and `size`, a wrapping tape contains every index
+pub fn inside(boundary: Boundary, size: usize, index: i64) -> bool {
+    match boundary {
+        Boundary::Classic => (0..size as i64).contains(&index),
+        Boundary::Wrap => true,
+        Boundary::Infinite => internal(index) < size,
+    }
+}
+
+/// In the infinite mode we need to store cells with a negative index.
+/// Since I was too bored to implements some real negative indexes or
+/// something, when you use a positive indexe it will use the even number
+/// and the odd number for negatives indexes.
+/// Here is a scheme to understand how you number are placed in the tape:
+/// ```text
+/// .---+----+---+----+---+----+---+----.
+/// | 0 | -1 | 1 | -2 | 2 | -3 | 3 | -4 | indexes you provide
+/// '---+----+---+----+---+----+---+----'
+///   0   1    2   3    4   5    6   7    indexes in the internal vector
+/// ```
+fn internal(i: i64) -> usize {
+    if i >= 0 {
+        (i * 2) as usize
+    } else {
+        (i * -2 - 1) as usize
+    }
+}
+
+/// The index of the cell stored at `i` in the internal vector, the opposite
+/// of `internal`
+fn external(i: usize) -> i64 {
+    match i % 2 {
+        0 => (i / 2) as i64,
+        _ => -((i + 1) as i64 / 2),
+    }
+}
+
+/// You can index into the tape as if it was an array.
+/// The index must come from `Tape::offset` so it is inside the tape.
+/// In the infinite mode you don’t need to increase the size of the array or
+/// choose a size at start, the tape will grow to the index you use.
+impl<C: Cell> Index<i32> for Tape<C> {
+    type Output = C;
+    fn index(&self, i: i32) -> &Self::Output {
+        // if we don’t have the mutability we can’t allocate
+        // the missings cells
+        self.vec.get(self.position(i)).unwrap_or(&self.zero)
+    }
+}
 
+impl<C: Cell> IndexMut<i32> for Tape<C> {
+    fn index_mut(&mut self, i: i32) -> &mut Self::Output {
+        let i = self.position(i);
         if i >= self.vec.len() {
-            self.vec.append(&mut vec![0; i - self.vec.len() + 1]);
+            self.vec.resize(i + 1, C::default());
         }
         &mut self.vec[i]
     }
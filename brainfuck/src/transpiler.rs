@@ -0,0 +1,135 @@
+use crate::cell::Overflow;
+use crate::compiler::Instruction;
+use crate::options::CellWidth;
+use crate::tape::Boundary;
+use crate::vm::{Config, Eof};
+use std::fmt::Write;
+
+/// Translate the program into a self-contained C file which behaves exactly
+/// like the `Vm` with the same cells and configuration.
+/// Only the wrapping bounded cells and the programs without extensions are
+/// supported.
+pub fn to_c(
+    instructions: &[Instruction],
+    cell: CellWidth,
+    config: &Config,
+) -> Result<String, String> {
+    let cell = match cell {
+        CellWidth::U8 => "uint8_t",
+        CellWidth::U16 => "uint16_t",
+        CellWidth::U32 => "uint32_t",
+        CellWidth::U64 => "uint64_t",
+        CellWidth::Bignum => return Err(String::from("bignum cells can’t be translated to C")),
+    };
+    if config.overflow != Overflow::Wrap {
+        return Err(String::from("only wrapping cells can be translated to C"));
+    }
+    if instructions.iter().any(Instruction::is_extension) {
+        return Err(String::from("only brainfuck can be translated to C"));
+    }
+
+    // the index of the cell 0 in the C array, an infinite tape holds as many
+    // cells on its left than on its right
+    let origin = match config.boundary {
+        Boundary::Classic | Boundary::Wrap => 0,
+        Boundary::Infinite => config.tape_size / 2,
+    };
+    let at = match config.boundary {
+        Boundary::Classic | Boundary::Infinite => {
+            "    long target = p + offset;
+    if (target < 0 || target >= TAPE_SIZE) {
+        fflush(stdout);
+        fprintf(stderr, \"Runtime error: the cell %ld is outside of the tape\\n\", target - ORIGIN);
+        exit(1);
+    }
+    return target;"
+        }
+        Boundary::Wrap => "    return ((p + offset) % TAPE_SIZE + TAPE_SIZE) % TAPE_SIZE;",
+    };
+    let eof = match config.eof {
+        Eof::Zero => "tape[p] = 0;",
+        Eof::MinusOne => "tape[p] = (cell)-1;",
+        Eof::Unchanged => "",
+    };
+
+    let mut c = String::new();
+    // writing to a `String` never fails
+    write!(
+        c,
+        "#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+typedef {cell} cell;
+#define TAPE_SIZE {size}L
+#define ORIGIN {origin}L
+
+static cell *tape;
+static long p = ORIGIN;
+
+/* index of the cell at `offset` from the memory pointer */
+static inline long at(long offset) {{
+{at}
+}}
+
+static inline void input(void) {{
+    int c;
+    fflush(stdout);
+    c = getchar();
+    if (c != EOF) {{
+        tape[p] = (cell)c;
+    }} else if (ferror(stdin)) {{
+        fprintf(stderr, \"Runtime error: I/O error: can’t read the input\\n\");
+        exit(1);
+    }} else {{
+        {eof}
+    }}
+}}
+
+int main(void) {{
+    tape = calloc(TAPE_SIZE, sizeof(cell));
+    if (tape == NULL) {{
+        fprintf(stderr, \"can’t allocate the tape\\n\");
+        return 1;
+    }}
+",
+        cell = cell,
+        size = config.tape_size,
+        origin = origin,
+        at = at,
+        eof = eof,
+    )
+    .unwrap();
+
+    let mut depth = 1;
+    for instruction in instructions {
+        if let Instruction::JumpIfNotZero(_) = instruction {
+            depth -= 1;
+        }
+        let indent = "    ".repeat(depth);
+        let line = match *instruction {
+            Instruction::Add(value) => format!("tape[p] += (cell){};", value),
+            Instruction::Move(offset) => format!("p = at({});", offset),
+            Instruction::Output => String::from("putchar(tape[p]);"),
+            Instruction::Input => String::from("input();"),
+            Instruction::JumpIfZero(_) => String::from("while (tape[p]) {"),
+            Instruction::JumpIfNotZero(_) => String::from("}"),
+            Instruction::Clear => String::from("tape[p] = 0;"),
+            // the multiplication is done on 64 bits to avoid any signed overflow
+            Instruction::MulAdd { offset, factor } => format!(
+                "if (tape[p]) tape[at({})] += (cell)((uint64_t)tape[p] * (uint64_t)(cell){});",
+                offset, factor
+            ),
+            Instruction::Scan(step) => format!("while (tape[p]) p = at({});", step),
+            Instruction::Breakpoint => continue,
+            _ => unreachable!(),
+        };
+        writeln!(c, "{}{}", indent, line).unwrap();
+        if let Instruction::JumpIfZero(_) = instruction {
+            depth += 1;
+        }
+    }
+
+    c.push_str("    fflush(stdout);\n    return 0;\n}\n");
+    Ok(c)
+}
@@ -1,126 +1,945 @@
-use std::io::{Read, Write};
+use crate::cell::{Cell, Overflow};
+use crate::compiler::Instruction;
+use crate::tape::{Boundary, Tape};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::time::Duration;
 
-pub struct Vm<'a> {
-    instructions: &'a [char],
+/// The value stored by `,` when there is no more input
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Eof {
+    Zero,
+    /// -1, which means all the bits of the cell are set
+    MinusOne,
+    /// The cell is left unchanged
+    Unchanged,
+}
+
+/// The settings of the `Vm`
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    pub overflow: Overflow,
+    pub eof: Eof,
+    pub boundary: Boundary,
+    /// Number of cells of the tape, or its maximum with `Boundary::Infinite`
+    pub tape_size: usize,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The cell at this index overflowed while the overflow mode is `Overflow::Error`
+    Overflow(i32),
+    /// The memory pointer moved to this cell which is outside of the tape
+    OutOfTape(i64),
+    /// Reading the input or writing the output failed
+    Io(std::io::Error),
+    /// A pbrain procedure was called with this number but never defined
+    UndefinedProcedure(String),
+    /// The state given to `Vm::restore` can’t be restored
+    State(String),
+    /// The program didn’t finish within this number of instructions
+    OutOfBudget(u64),
+    /// The program didn’t finish within this time
+    Timeout(Duration),
+}
+
+/// Why `Vm::run_for` returned
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Finished,
+    /// All the instructions allowed were executed
+    OutOfBudget,
+    /// The input has nothing to read yet, the next instruction is a `,` that
+    /// will read it once the `Vm` is run again
+    WaitingForInput,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Overflow(cell) => write!(f, "overflow of the cell {}", cell),
+            Error::OutOfTape(cell) => write!(f, "the cell {} is outside of the tape", cell),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::UndefinedProcedure(number) => {
+                write!(f, "the procedure {} is not defined", number)
+            }
+            Error::State(e) => write!(f, "invalid saved state: {}", e),
+            Error::OutOfBudget(budget) => {
+                write!(f, "the program didn’t finish in {} instructions", budget)
+            }
+            Error::Timeout(timeout) => {
+                write!(f, "the program didn’t finish in {:?}", timeout)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The first line of a state saved by `Vm::save`
+const STATE_HEADER: &str = "brainfuck vm state 1";
+
+/// A Brainfork thread waiting for its turn
+#[derive(Clone)]
+struct Thread {
+    instruction_pointer: usize,
+    memory_pointer: i32,
+    call_stack: Vec<usize>,
+}
+
+/// What is needed to undo one cycle of the `Vm`
+struct Undo<C: Cell> {
     instruction_pointer: usize,
-    tape: crate::tape::Tape,
     memory_pointer: i32,
+    /// the cells modified by the instruction and their previous value
+    cells: [Option<(i32, C)>; 2],
+    /// the byte read by the instruction, it must be read again
+    input: Option<u8>,
+    /// the rest of the state, only for the instructions modifying it or when
+    /// there are many threads
+    others: Option<Box<Others<C>>>,
 }
 
-impl<'a> Vm<'a> {
-    pub fn new(instructions: &'a [char]) -> Self {
+/// The state of the `Vm` used by the extensions of brainfuck
+struct Others<C: Cell> {
+    procedures: Vec<(C, usize)>,
+    call_stack: Vec<usize>,
+    threads: VecDeque<Thread>,
+    storage: C,
+}
+
+/// The last cycles executed by the `Vm`, the oldest are forgotten once there
+/// are more than `limit` of them
+struct History<C: Cell> {
+    limit: usize,
+    steps: VecDeque<Undo<C>>,
+}
+
+/// Execute the instructions on a tape.
+/// With Brainfork there can be many threads sharing the tape, the `Vm` runs
+/// the current one and they take turns after each instruction.
+pub struct Vm<'a, C: Cell, R: Read, W: Write> {
+    instructions: &'a [Instruction],
+    instruction_pointer: usize,
+    tape: Tape<C>,
+    memory_pointer: i32,
+    overflow: Overflow,
+    eof: Eof,
+    /// the number of each pbrain procedure and the index of its first instruction
+    procedures: Vec<(C, usize)>,
+    /// where to go back when the current procedure returns
+    call_stack: Vec<usize>,
+    /// the other Brainfork threads, in the order they will run
+    threads: VecDeque<Thread>,
+    /// the storage register of Extended Brainfuck
+    storage: C,
+    /// identify the instructions, the configuration and the cells of the `Vm`
+    /// in its saved states
+    hash: u64,
+    /// the input restored from a saved state or by undoing a cycle, read
+    /// before `input`
+    pending: VecDeque<u8>,
+    /// the number of instructions executed by `run_for`
+    executed: u64,
+    /// the most cells a `Scan` moves over in one cycle, it goes on during the
+    /// next cycles if it didn’t reach a 0
+    scan_limit: u64,
+    /// the cells moved over by the last `Scan`, `run_for` counts each of them
+    /// as one instruction
+    moved: u64,
+    /// only recorded when asked, to undo the last cycles
+    history: Option<History<C>>,
+    input: BufReader<R>,
+    /// flushed before reading the input and when the `Vm` is dropped
+    output: BufWriter<W>,
+}
+
+impl<'a, C: Cell, R: Read, W: Write> Vm<'a, C, R, W> {
+    pub fn new(instructions: &'a [Instruction], config: Config, input: R, output: W) -> Self {
         Vm {
-            instructions: instructions,
+            instructions,
             instruction_pointer: 0,
-            tape: crate::tape::Tape::new(),
+            tape: Tape::new(config.boundary, config.tape_size),
             memory_pointer: 0,
+            overflow: config.overflow,
+            eof: config.eof,
+            procedures: Vec::new(),
+            call_stack: Vec::new(),
+            threads: VecDeque::new(),
+            storage: C::default(),
+            hash: hash::<C>(instructions, config),
+            pending: VecDeque::new(),
+            executed: 0,
+            scan_limit: config.tape_size as u64,
+            moved: 0,
+            history: None,
+            input: BufReader::new(input),
+            output: BufWriter::new(output),
         }
     }
 
-    pub fn cycle(&mut self) {
-        let op = self.instructions[self.instruction_pointer];
-        match op {
-            '>' => self.right(),
-            '<' => self.left(),
-            '+' => self.increment(),
-            '-' => self.decrement(),
-            '.' => self.output(),
-            ',' => self.input(),
-            '[' => self.jump_right(),
-            ']' => self.jump_left(),
-            _ => self.instruction_pointer += 1, // comment
+    /// Execute the next instruction of the current thread, then let the
+    /// next thread run
+    pub fn cycle(&mut self) -> Result<(), Error> {
+        if self.history.is_some() {
+            self.remember();
+        }
+        self.moved = 0;
+        if let Err(e) = self.step() {
+            // nothing changed, there is nothing to undo
+            if let Some(history) = &mut self.history {
+                history.steps.pop_back();
+            }
+            return Err(e);
+        }
+        if !self.threads.is_empty() {
+            self.switch();
         }
+        Ok(())
+    }
+
+    /// Execute at most `budget` instructions, stop earlier if the program is
+    /// finished or if its input is not ready, which is only possible with an
+    /// input returning `ErrorKind::WouldBlock`.
+    /// Each cell a `Scan` moves over counts as one instruction.
+    pub fn run_for(&mut self, budget: u64) -> Result<Status, Error> {
+        let scan_limit = self.scan_limit;
+        let mut executed = 0;
+        let status = loop {
+            if self.finished() {
+                break Ok(Status::Finished);
+            }
+            if executed == budget {
+                break Ok(Status::OutOfBudget);
+            }
+            self.scan_limit = scan_limit.min(budget - executed);
+            match self.cycle() {
+                Ok(()) => executed += self.moved.max(1),
+                // nothing was read so the `,` can be executed again
+                Err(Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => {
+                    break Ok(Status::WaitingForInput)
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        self.scan_limit = scan_limit;
+        self.executed += executed;
+        status
+    }
+
+    /// The number of instructions executed by `run_for`
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
+    /// Record what is needed to undo the next `limit` cycles with `undo`
+    pub fn record(&mut self, limit: usize) {
+        self.history = Some(History {
+            limit,
+            steps: VecDeque::new(),
+        });
     }
 
+    /// Undo the last cycle, return `false` if there is nothing to undo.
+    /// The output of the cycle can’t be undone, but its input is read again.
+    pub fn undo(&mut self) -> bool {
+        let undo = match self.history.as_mut().and_then(|h| h.steps.pop_back()) {
+            Some(undo) => undo,
+            None => return false,
+        };
+        self.instruction_pointer = undo.instruction_pointer;
+        self.memory_pointer = undo.memory_pointer;
+        for (index, value) in undo.cells.iter().flatten() {
+            self.tape[*index] = value.clone();
+        }
+        if let Some(byte) = undo.input {
+            self.pending.push_front(byte);
+        }
+        if let Some(others) = undo.others {
+            self.procedures = others.procedures;
+            self.call_stack = others.call_stack;
+            self.threads = others.threads;
+            self.storage = others.storage;
+        }
+        true
+    }
+
+    /// Record what the next cycle is going to modify
+    fn remember(&mut self) {
+        let pointer = self.memory_pointer;
+        let cell = |index: Option<i32>| index.map(|i| (i, self.tape[i].clone()));
+        let instruction = self.instructions[self.instruction_pointer];
+        let cells = match instruction {
+            Instruction::Add(_)
+            | Instruction::Input
+            | Instruction::InputOrOutput
+            | Instruction::Clear
+            | Instruction::Load
+            | Instruction::Bitwise(_) => [cell(Some(pointer)), None],
+            Instruction::MulAdd { offset, .. } => [cell(self.tape.offset(pointer, offset)), None],
+            Instruction::Fork => [cell(Some(pointer)), cell(self.tape.offset(pointer, 1))],
+            _ => [None, None],
+        };
+        let others = match instruction {
+            _ if !self.threads.is_empty() => true,
+            Instruction::Define(_)
+            | Instruction::Return
+            | Instruction::Call
+            | Instruction::Fork
+            | Instruction::Store
+            | Instruction::End => true,
+            _ => false,
+        };
+        let undo = Undo {
+            instruction_pointer: self.instruction_pointer,
+            memory_pointer: pointer,
+            cells,
+            input: None,
+            others: match others {
+                true => Some(Box::new(Others {
+                    procedures: self.procedures.clone(),
+                    call_stack: self.call_stack.clone(),
+                    threads: self.threads.clone(),
+                    storage: self.storage.clone(),
+                })),
+                false => None,
+            },
+        };
+
+        let history = self.history.as_mut().unwrap();
+        if history.steps.len() == history.limit {
+            history.steps.pop_front();
+        }
+        history.steps.push_back(undo);
+    }
+
+    fn step(&mut self) -> Result<(), Error> {
+        match self.instructions[self.instruction_pointer] {
+            Instruction::Add(value) => self.add(value)?,
+            Instruction::Move(offset) => self.memory_pointer = self.offset(offset)?,
+            Instruction::Output => self.output()?,
+            Instruction::Input => self.input()?,
+            Instruction::InputOrOutput => match self.tape[self.memory_pointer].is_zero() {
+                true => self.input()?,
+                false => self.output()?,
+            },
+            Instruction::JumpIfZero(target) => {
+                if self.tape[self.memory_pointer].is_zero() {
+                    self.instruction_pointer = target;
+                    return Ok(());
+                }
+            }
+            Instruction::JumpIfNotZero(target) => {
+                if !self.tape[self.memory_pointer].is_zero() {
+                    self.instruction_pointer = target;
+                    return Ok(());
+                }
+            }
+            Instruction::Clear => self.tape[self.memory_pointer] = C::default(),
+            Instruction::MulAdd { offset, factor } => self.mul_add(offset, factor)?,
+            Instruction::Scan(step) => {
+                if !self.scan(step)? {
+                    return Ok(());
+                }
+            }
+            Instruction::Breakpoint => (),
+            Instruction::Define(target) => {
+                self.define(self.instruction_pointer + 1);
+                self.instruction_pointer = target;
+                return Ok(());
+            }
+            Instruction::Return => {
+                if let Some(target) = self.call_stack.pop() {
+                    self.instruction_pointer = target;
+                    return Ok(());
+                }
+            }
+            Instruction::Call => {
+                self.call()?;
+                return Ok(());
+            }
+            Instruction::Fork => self.fork()?,
+            Instruction::Store => self.storage = self.tape[self.memory_pointer].clone(),
+            Instruction::Load => self.tape[self.memory_pointer] = self.storage.clone(),
+            Instruction::Bitwise(op) => {
+                let v = &mut self.tape[self.memory_pointer];
+                *v = v.bitwise(op, &self.storage);
+            }
+            Instruction::End => {
+                // all the threads stop
+                self.threads.clear();
+                self.instruction_pointer = self.instructions.len();
+                return Ok(());
+            }
+        }
+        self.instruction_pointer += 1;
+        Ok(())
+    }
+
+    /// Return `true` once all the threads are finished
     pub fn finished(&self) -> bool {
         self.instruction_pointer >= self.instructions.len()
     }
 
-    /// Move the memory pointer to the right
-    fn right(&mut self) {
-        self.memory_pointer += 1;
-        self.instruction_pointer += 1;
+    /// The index of the next instruction to execute in the current thread
+    pub fn instruction_pointer(&self) -> usize {
+        self.instruction_pointer
     }
 
-    /// Move the memory pointer to the left
-    fn left(&mut self) {
-        self.memory_pointer -= 1;
-        self.instruction_pointer += 1;
+    pub fn memory_pointer(&self) -> i32 {
+        self.memory_pointer
     }
 
-    /// Increment the memory cell under the memory pointer
-    fn increment(&mut self) {
-        let v = &mut self.tape[self.memory_pointer];
-        *v = v.wrapping_add(1);
-        self.instruction_pointer += 1;
+    /// Return the cell at this index, or `None` if it is outside of the tape
+    pub fn cell(&self, index: i32) -> Option<&C> {
+        self.tape.offset(index, 0).map(|i| &self.tape[i])
     }
 
-    /// Decrement the memory cell under the memory pointer
-    fn decrement(&mut self) {
-        let v = &mut self.tape[self.memory_pointer];
-        *v = v.wrapping_sub(1);
-        self.instruction_pointer += 1;
+    /// Set the cell at this index
+    pub fn set_cell(&mut self, index: i32, value: C) -> Result<(), Error> {
+        let i = self
+            .tape
+            .offset(index, 0)
+            .ok_or(Error::OutOfTape(index as i64))?;
+        self.tape[i] = value;
+        Ok(())
     }
 
-    /// Output the character signified by the cell at the memory pointer
-    fn output(&mut self) {
-        write!(
-            std::io::stdout(),
-            "{}",
-            self.tape[self.memory_pointer] as u8 as char // because I can’t cast i8 to char
-        )
-        .unwrap();
-        self.instruction_pointer += 1;
+    /// Write all the output buffered by the `Vm`
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.output.flush().map_err(Error::Io)
     }
 
-    /// Input a character and store it in the cell at the memory pointer
-    /// **Here we decide to send 0 to the brainfuck program when we get EOF**
-    fn input(&mut self) {
-        self.tape[self.memory_pointer] =
-            std::io::stdin().bytes().next().unwrap_or(Ok(0)).unwrap() as i8;
-        self.instruction_pointer += 1;
+    /// Return the index of the cell at `offset` from the memory pointer
+    fn offset(&self, offset: i32) -> Result<i32, Error> {
+        self.tape
+            .offset(self.memory_pointer, offset)
+            .ok_or(Error::OutOfTape(self.memory_pointer as i64 + offset as i64))
+    }
+
+    /// Add a value to the memory cell under the memory pointer
+    fn add(&mut self, value: i32) -> Result<(), Error> {
+        let v = &mut self.tape[self.memory_pointer];
+        *v = v
+            .add(value, self.overflow)
+            .ok_or(Error::Overflow(self.memory_pointer))?;
+        Ok(())
     }
 
-    /// Jump past the matching ] if the cell under the memory pointer is 0
-    fn jump_right(&mut self) {
-        if self.tape[self.memory_pointer] != 0 {
-            self.instruction_pointer += 1;
-            return;
+    /// Add the memory cell under the memory pointer multiplied by `factor`
+    /// to the cell at `offset`. Nothing happens if it’s 0, like the loop it
+    /// comes from which is skipped.
+    fn mul_add(&mut self, offset: i32, factor: i32) -> Result<(), Error> {
+        let value = self.tape[self.memory_pointer].clone();
+        if value.is_zero() {
+            return Ok(());
         }
-        let mut matching = 1;
-        loop {
-            self.instruction_pointer += 1;
-            if self.instructions[self.instruction_pointer] == '[' {
-                matching += 1;
+        let target = self.offset(offset)?;
+        let v = &mut self.tape[target];
+        *v = v
+            .add_mul(&value, factor, self.overflow)
+            .ok_or(Error::Overflow(target))?;
+        Ok(())
+    }
+
+    /// Move the memory pointer by `step` until it reaches a cell containing 0,
+    /// over `scan_limit` cells at most. Return `false` if it didn’t reach a 0
+    /// yet, the scan goes on during the next cycle.
+    /// If the scan goes outside of the tape the memory pointer doesn’t move.
+    fn scan(&mut self, step: i32) -> Result<bool, Error> {
+        let start = self.memory_pointer;
+        while !self.tape[self.memory_pointer].is_zero() {
+            if self.moved == self.scan_limit {
+                return Ok(false);
             }
-            if self.instructions[self.instruction_pointer] == ']' {
-                matching -= 1;
-                if matching == 0 {
-                    break;
+            match self.offset(step) {
+                Ok(next) => self.memory_pointer = next,
+                Err(e) => {
+                    self.memory_pointer = start;
+                    return Err(e);
                 }
             }
+            self.moved += 1;
         }
-        self.instruction_pointer += 1;
+        Ok(true)
+    }
+
+    /// Define the procedure starting at `start` with the number in the cell
+    /// under the memory pointer, replacing the previous one with this number
+    fn define(&mut self, start: usize) {
+        let number = &self.tape[self.memory_pointer];
+        match self.procedures.iter_mut().find(|(n, _)| n == number) {
+            Some((_, s)) => *s = start,
+            None => self.procedures.push((number.clone(), start)),
+        }
+    }
+
+    /// Jump to the procedure whose number is in the cell under the memory pointer
+    fn call(&mut self) -> Result<(), Error> {
+        let number = &self.tape[self.memory_pointer];
+        let start = self
+            .procedures
+            .iter()
+            .find(|(n, _)| n == number)
+            .map(|&(_, start)| start)
+            .ok_or_else(|| Error::UndefinedProcedure(number.to_string()))?;
+        self.call_stack.push(self.instruction_pointer + 1);
+        self.instruction_pointer = start;
+        Ok(())
     }
 
-    /// Jump back to the matching [ if the cell under the memory pointer is nonzero
-    fn jump_left(&mut self) {
-        if self.tape[self.memory_pointer] == 0 {
-            self.instruction_pointer += 1;
-            return;
+    /// Create a thread executing the next instruction with the memory pointer
+    /// one cell to the right, where the cell is set to 1. The cell of the
+    /// current thread is set to 0.
+    fn fork(&mut self) -> Result<(), Error> {
+        let child = self.offset(1)?;
+        self.tape[self.memory_pointer] = C::default();
+        self.tape[child] = C::default().add(1, Overflow::Wrap).unwrap();
+        // a thread forked at the end of the program is already finished
+        if self.instruction_pointer + 1 < self.instructions.len() {
+            self.threads.push_back(Thread {
+                instruction_pointer: self.instruction_pointer + 1,
+                memory_pointer: child,
+                call_stack: self.call_stack.clone(),
+            });
         }
-        let mut matching = 1;
-        loop {
-            self.instruction_pointer -= 1;
-            if self.instructions[self.instruction_pointer] == ']' {
-                matching += 1;
+        Ok(())
+    }
+
+    /// Give its turn to the next thread, the current thread waits for its
+    /// next turn or is dropped if it’s finished
+    fn switch(&mut self) {
+        let next = self.threads.pop_front().unwrap();
+        let current = Thread {
+            instruction_pointer: std::mem::replace(
+                &mut self.instruction_pointer,
+                next.instruction_pointer,
+            ),
+            memory_pointer: std::mem::replace(&mut self.memory_pointer, next.memory_pointer),
+            call_stack: std::mem::replace(&mut self.call_stack, next.call_stack),
+        };
+        if current.instruction_pointer < self.instructions.len() {
+            self.threads.push_back(current);
+        }
+    }
+
+    /// Output the character signified by the cell at the memory pointer
+    fn output(&mut self) -> Result<(), Error> {
+        let byte = self.tape[self.memory_pointer].to_byte();
+        self.output.write_all(&[byte]).map_err(Error::Io)
+    }
+
+    /// Input a character and store it in the cell at the memory pointer.
+    /// On EOF the cell is updated according to the `Eof` policy of the `Vm`
+    fn input(&mut self) -> Result<(), Error> {
+        // the user needs to see everything we printed before answering
+        self.flush()?;
+
+        let mut byte = [0];
+        let read = match self.pending.pop_front() {
+            Some(pending) => {
+                byte[0] = pending;
+                Ok(())
             }
-            if self.instructions[self.instruction_pointer] == '[' {
-                matching -= 1;
-                if matching == 0 {
-                    break;
+            None => self.input.read_exact(&mut byte),
+        };
+        let value = match read {
+            Ok(()) => {
+                let undo = self.history.as_mut().and_then(|h| h.steps.back_mut());
+                if let Some(undo) = undo {
+                    undo.input = Some(byte[0]);
+                }
+                C::from_byte(byte[0])
+            }
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => match self.eof {
+                Eof::Zero => C::default(),
+                Eof::MinusOne => C::default().add(-1, Overflow::Wrap).unwrap(),
+                Eof::Unchanged => return Ok(()),
+            },
+            Err(e) => return Err(Error::Io(e)),
+        };
+        self.tape[self.memory_pointer] = value;
+        Ok(())
+    }
+
+    /// Save everything needed to resume the execution later with `restore`:
+    /// the pointers, the tape and the input already read but not consumed.
+    /// The output is flushed first so nothing is lost if we resume from here.
+    pub fn save(&mut self, mut out: impl Write) -> Result<(), Error> {
+        self.flush()?;
+        self.write_state(&mut out).map_err(Error::Io)
+    }
+
+    fn write_state(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let numbers =
+            |numbers: &[usize]| -> String { numbers.iter().map(|n| format!(" {}", n)).collect() };
+        writeln!(out, "{}", STATE_HEADER)?;
+        writeln!(out, "hash {:016x}", self.hash)?;
+        writeln!(out, "instruction_pointer {}", self.instruction_pointer)?;
+        writeln!(out, "memory_pointer {}", self.memory_pointer)?;
+        writeln!(out, "call_stack{}", numbers(&self.call_stack))?;
+        writeln!(out, "storage {}", self.storage)?;
+        for (number, start) in &self.procedures {
+            writeln!(out, "procedure {} {}", number, start)?;
+        }
+        for thread in &self.threads {
+            writeln!(
+                out,
+                "thread {} {}{}",
+                thread.instruction_pointer,
+                thread.memory_pointer,
+                numbers(&thread.call_stack)
+            )?;
+        }
+        for (index, cell) in self.tape.cells() {
+            writeln!(out, "cell {} {}", index, cell)?;
+        }
+        let input: String = self
+            .pending
+            .iter()
+            .chain(self.input.buffer())
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        writeln!(out, "input {}", input)
+    }
+
+    /// Restore a state written by `save`, the `Vm` must be new and created
+    /// with the same instructions, configuration and type of cells.
+    pub fn restore(&mut self, state: &str) -> Result<(), Error> {
+        let mut lines = state.lines();
+        if lines.next() != Some(STATE_HEADER) {
+            return Err(Error::State(String::from("unknown format")));
+        }
+        let mut hash = None;
+        for line in lines {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["hash", h] => hash = Some(u64::from_str_radix(h, 16).map_err(invalid(h))?),
+                ["instruction_pointer", ip] => self.instruction_pointer = parse(ip)?,
+                ["memory_pointer", mp] => self.memory_pointer = parse(mp)?,
+                ["call_stack", stack @ ..] => {
+                    self.call_stack = stack.iter().map(|n| parse(n)).collect::<Result<_, _>>()?
+                }
+                ["storage", value] => self.storage = parse(value)?,
+                ["procedure", number, start] => {
+                    self.procedures.push((parse(number)?, parse(start)?))
+                }
+                ["thread", ip, mp, stack @ ..] => self.threads.push_back(Thread {
+                    instruction_pointer: parse(ip)?,
+                    memory_pointer: parse(mp)?,
+                    call_stack: stack.iter().map(|n| parse(n)).collect::<Result<_, _>>()?,
+                }),
+                ["cell", index, value] => {
+                    let index = parse(index)?;
+                    let i = self
+                        .tape
+                        .offset(index, 0)
+                        .ok_or(Error::OutOfTape(index as i64))?;
+                    self.tape[i] = parse(value)?;
                 }
+                ["input"] => (),
+                ["input", bytes] if bytes.len() % 2 == 0 => {
+                    for i in (0..bytes.len()).step_by(2) {
+                        let byte = bytes.get(i..i + 2).ok_or_else(|| invalid(bytes)(()))?;
+                        let byte = u8::from_str_radix(byte, 16).map_err(invalid(bytes))?;
+                        self.pending.push_back(byte);
+                    }
+                }
+                _ => return Err(Error::State(format!("invalid line `{}`", line))),
             }
         }
+        match hash {
+            Some(hash) if hash == self.hash => self.check_pointers(),
+            Some(_) => Err(Error::State(String::from(
+                "it was saved with another program or other options",
+            ))),
+            None => Err(Error::State(String::from("the hash is missing"))),
+        }
+    }
+
+    /// Check that the pointers of a restored state are inside the program and
+    /// the tape, a state edited by hand must not make the `Vm` panic
+    fn check_pointers(&self) -> Result<(), Error> {
+        let current = (
+            self.instruction_pointer,
+            self.memory_pointer,
+            &self.call_stack,
+        );
+        let threads = self
+            .threads
+            .iter()
+            .map(|t| (t.instruction_pointer, t.memory_pointer, &t.call_stack));
+        let procedures = self.procedures.iter().map(|(_, start)| *start);
+        for (ip, mp, call_stack) in std::iter::once(current).chain(threads) {
+            // the end of the program is where a finished thread is
+            let outside = std::iter::once(ip)
+                .chain(call_stack.iter().copied())
+                .chain(procedures.clone())
+                .find(|&ip| ip > self.instructions.len());
+            if let Some(ip) = outside {
+                return Err(Error::State(format!(
+                    "the instruction {} is outside of the program",
+                    ip
+                )));
+            }
+            self.tape.offset(mp, 0).ok_or(Error::OutOfTape(mp as i64))?;
+        }
+        Ok(())
+    }
+}
+
+/// Identify the instructions, the configuration and the cells of a `Vm`
+/// with the FNV-1a hash of their description
+fn hash<C: Cell>(instructions: &[Instruction], config: Config) -> u64 {
+    let description = format!(
+        "{:?} {:?} {}",
+        instructions,
+        config,
+        std::any::type_name::<C>()
+    );
+    description.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Return an error for an invalid value in a saved state
+fn invalid<E>(value: &str) -> impl Fn(E) -> Error + '_ {
+    move |_| Error::State(format!("invalid value `{}`", value))
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> Result<T, Error> {
+    value.parse().map_err(invalid(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{self, Folding};
+    use crate::parser::{self, Extensions};
+    use num_bigint::BigInt;
+
+    const CONFIG: Config = Config {
+        overflow: Overflow::Wrap,
+        eof: Eof::Zero,
+        boundary: Boundary::Classic,
+        tape_size: 16,
+    };
+
+    /// Run an Extended Brainfuck program, return its first cells and its output
+    fn run<C: Cell>(source: &str, config: Config, input: &[u8]) -> (Vec<C>, Vec<u8>) {
+        let source: Vec<char> = source.chars().collect();
+        let extensions = Extensions {
+            extended: true,
+            brainfork: true,
+            ..Extensions::default()
+        };
+        let program = parser::parse(&parser::positions(&source), extensions).unwrap();
+        let compiled = compiler::compile(&program, Folding::new(C::BOUNDED, config.overflow));
+        let mut output = Vec::new();
+        let cells = {
+            let mut vm = Vm::<C, _, _>::new(&compiled.instructions, config, input, &mut output);
+            while !vm.finished() {
+                vm.cycle().unwrap();
+            }
+            (0..4).map(|i| vm.cell(i).unwrap().clone()).collect()
+        };
+        (cells, output)
+    }
+
+    fn tape<C: Cell>(source: &str) -> Vec<C> {
+        run(source, CONFIG, &[]).0
+    }
+
+    fn bignums(values: &[i64]) -> Vec<BigInt> {
+        values.iter().map(|&v| BigInt::from(v)).collect()
+    }
+
+    #[test]
+    fn end() {
+        let (cells, output) = run::<u8>(
+            "+++++++++++++++++++++++++++++++++++++++++++++++++.@.+",
+            CONFIG,
+            &[],
+        );
+        assert_eq!(cells, [49, 0, 0, 0]);
+        assert_eq!(output, b"1");
+        // the code after `@` is never executed, even a loop
+        assert_eq!(tape::<u8>("+@>+"), [1, 0, 0, 0]);
+        assert_eq!(tape::<u8>("+@[-]>+"), [1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn end_stops_every_thread() {
+        // the child would loop forever if the parent didn’t end the program
+        assert_eq!(tape::<u8>("Y[>+<]@"), [0, 1, 0, 0]);
+    }
+
+    /// Run a Brainfork program compiled like the brainfuck CLI does, return its output
+    fn threads<C: Cell>(source: &str) -> Vec<u8> {
+        let source: Vec<char> = source.chars().collect();
+        let extensions = Extensions {
+            brainfork: true,
+            ..Extensions::default()
+        };
+        let program = parser::parse(&parser::positions(&source), extensions).unwrap();
+        let compiled = compiler::compile(&program, Folding::unfolded());
+        let mut output = Vec::new();
+        let mut vm = Vm::<C, _, _>::new(&compiled.instructions, CONFIG, &[][..], &mut output);
+        while !vm.finished() {
+            vm.cycle().unwrap();
+        }
+        drop(vm);
+        output
+    }
+
+    #[test]
+    fn threads_take_the_same_turns_with_any_cells() {
+        let source = "Y[>++++++++[-]<-<++++++++++++++++++++++++++++++++++++++++++++++++>]\
+                      ><><><><><><><><><><.";
+        let output = threads::<u8>(source);
+        assert_eq!(output, b"\0\0");
+        assert_eq!(threads::<u16>(source), output);
+        assert_eq!(threads::<u64>(source), output);
+        assert_eq!(threads::<BigInt>(source), output);
+    }
+
+    #[test]
+    fn store_and_load() {
+        assert_eq!(tape::<u8>("+++$>!>!"), [3, 3, 3, 0]);
+        // the register keeps its value when the cell changes
+        assert_eq!(tape::<u8>("++$+++>!"), [5, 2, 0, 0]);
+        // the register starts at 0
+        assert_eq!(tape::<u8>("+++!"), [0, 0, 0, 0]);
+        assert_eq!(tape::<BigInt>("---$>!"), bignums(&[-3, -3, 0, 0]));
+    }
+
+    #[test]
+    fn shift_left() {
+        assert_eq!(tape::<u8>("+++{>-{"), [6, 254, 0, 0]);
+        assert_eq!(tape::<u16>("+++{>-{"), [6, 65534, 0, 0]);
+        assert_eq!(tape::<u32>(">-{"), [0, u32::MAX - 1, 0, 0]);
+        assert_eq!(tape::<u64>(">-{"), [0, u64::MAX - 1, 0, 0]);
+        assert_eq!(tape::<BigInt>("+++{>-{"), bignums(&[6, -2, 0, 0]));
+    }
+
+    #[test]
+    fn shift_right() {
+        assert_eq!(tape::<u8>("+++}>-}"), [1, 127, 0, 0]);
+        assert_eq!(tape::<u16>("+++}>-}"), [1, 32767, 0, 0]);
+        assert_eq!(tape::<u64>(">-}"), [0, u64::MAX / 2, 0, 0]);
+        assert_eq!(tape::<BigInt>("+++}>-}"), bignums(&[1, -1, 0, 0]));
+    }
+
+    #[test]
+    fn not() {
+        assert_eq!(tape::<u8>("~>+~"), [255, 254, 0, 0]);
+        assert_eq!(tape::<u32>("~"), [u32::MAX, 0, 0, 0]);
+        assert_eq!(tape::<BigInt>("~>+~"), bignums(&[-1, -2, 0, 0]));
+    }
+
+    #[test]
+    fn xor() {
+        assert_eq!(tape::<u8>("+++++$>++++++^"), [5, 3, 0, 0]);
+        assert_eq!(tape::<u16>("-$>+^"), [65535, 65534, 0, 0]);
+        assert_eq!(tape::<BigInt>("+++++$>-------^"), bignums(&[5, -4, 0, 0]));
+    }
+
+    #[test]
+    fn and() {
+        assert_eq!(tape::<u8>("+++++$>++++++&"), [5, 4, 0, 0]);
+        assert_eq!(tape::<u64>("-$>+++&"), [u64::MAX, 3, 0, 0]);
+        assert_eq!(tape::<BigInt>("+++++$>-------&"), bignums(&[5, 1, 0, 0]));
+    }
+
+    #[test]
+    fn or() {
+        assert_eq!(tape::<u8>("+++++$>++++++|"), [5, 7, 0, 0]);
+        assert_eq!(tape::<u16>("++++++++{{{{{{{{$>+|"), [2048, 2049, 0, 0]);
+        assert_eq!(tape::<BigInt>("+++++$>-------|"), bignums(&[5, -3, 0, 0]));
+    }
+
+    #[test]
+    fn bitwise_never_overflows() {
+        let config = Config {
+            overflow: Overflow::Error,
+            ..CONFIG
+        };
+        assert_eq!(run::<u8>("~{", config, &[]).0, [254, 0, 0, 0]);
+        assert_eq!(run::<u8>("~", config, &[]).0, [255, 0, 0, 0]);
+    }
+
+    #[test]
+    fn shares_the_eof_policy() {
+        let config = Config {
+            eof: Eof::MinusOne,
+            ..CONFIG
+        };
+        assert_eq!(run::<u8>(",~", config, &[]).0, [0, 0, 0, 0]);
+        assert_eq!(run::<u16>(",}", config, &[]).0, [32767, 0, 0, 0]);
+        assert_eq!(run::<u8>(",~", CONFIG, &[]).0, [255, 0, 0, 0]);
+        assert_eq!(run::<u8>(",$>!{", CONFIG, b"A").0, [65, 130, 0, 0]);
+    }
+
+    #[test]
+    fn budget_stops_an_endless_scan() {
+        let source: Vec<char> = "+>+>+[>]".chars().collect();
+        let program = parser::parse(&parser::positions(&source), Extensions::default()).unwrap();
+        let compiled = compiler::compile(&program, Folding::new(true, Overflow::Wrap));
+        let config = Config {
+            boundary: Boundary::Wrap,
+            tape_size: 3,
+            ..CONFIG
+        };
+        let mut vm = Vm::<u8, _, _>::new(&compiled.instructions, config, &[][..], Vec::new());
+        // each cell the scan moves over is one instruction
+        assert_eq!(vm.run_for(100).unwrap(), Status::OutOfBudget);
+        assert_eq!(vm.executed(), 100);
+    }
+
+    #[test]
+    fn restore_checks_the_pointers() {
+        let source: Vec<char> = "+(>+)[:-]".chars().collect();
+        let extensions = Extensions {
+            pbrain: true,
+            ..Extensions::default()
+        };
+        let program = parser::parse(&parser::positions(&source), extensions).unwrap();
+        let compiled = compiler::compile(&program, Folding::new(true, Overflow::Wrap));
+        let new = || Vm::<u8, _, _>::new(&compiled.instructions, CONFIG, &[][..], Vec::new());
+        let mut vm = new();
+        vm.run_for(3).unwrap();
+        let mut state = Vec::new();
+        vm.save(&mut state).unwrap();
+        let state = String::from_utf8(state).unwrap();
+        assert!(new().restore(&state).is_ok());
+
+        let edited = |from: &str, to: &str| {
+            assert!(state.contains(from));
+            new().restore(&state.replace(from, to))
+        };
+        assert!(matches!(
+            edited("instruction_pointer 6", "instruction_pointer 99"),
+            Err(Error::State(_))
+        ));
+        assert!(matches!(
+            edited("procedure 1 2", "procedure 1 99"),
+            Err(Error::State(_))
+        ));
+        assert!(matches!(
+            edited("memory_pointer 0", "memory_pointer 16"),
+            Err(Error::OutOfTape(16))
+        ));
+        assert!(matches!(
+            edited("call_stack", "call_stack 99"),
+            Err(Error::State(_))
+        ));
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        let source: Vec<char> = "+@$!{}~^&|+".chars().collect();
+        let program = parser::parse(&parser::positions(&source), Extensions::default()).unwrap();
+        assert_eq!(program.code, ['+', '+']);
     }
 }